@@ -1,4 +1,4 @@
 #!/usr/bin/env python3
-with open('.shell.bin.o', 'r+b') as f:
+with open('.shell.elf.o', 'r+b') as f:
     f.seek(0x30)
     f.write(b'\x05\x00\x00\x00')
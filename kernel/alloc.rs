@@ -1,66 +1,197 @@
 use core::{alloc::{GlobalAlloc, Layout}, ptr::NonNull};
 
-// Do a linked list allocator next
+use crate::{lock::IrqSpinLock, traits::KSay};
 
-use spin::Mutex;
+#[global_allocator]
+pub static GLOBAL_ALLOC: LinkedListAlloc = LinkedListAlloc::new();
 
-use crate::paging::PageTable;
+/// Every block handed out or kept on the free list is a multiple of this in both size and
+/// address, so a free block can always hold a [`ListLink`] and leftovers from splitting are
+/// either empty or big enough to go back on the list.
+const BLOCK_GRANULE: usize = size_of::<ListLink>();
 
-#[global_allocator]
-pub static GLOBAL_ALLOC: BumpAlloc = BumpAlloc::new();
+#[derive(Debug)]
+pub enum AllocError {
+    OutOfMemory,
+}
 
+/// First-fit allocator over a free list kept sorted by address, so freed
+/// blocks can be coalesced with their neighbours.
 pub struct LinkedListAlloc {
-    head: Mutex<ListLink>
+    /// Sentinel, `size` is always 0 and `next` is the first free block. Interrupt handlers
+    /// allocate too.
+    head: IrqSpinLock<ListLink>
 }
 
-#[repr(C)]
+#[repr(C, align(16))]
 struct ListLink {
     size: usize,
     next: Option<NonNull<ListLink>>
 }
 
-struct RawBump {
-    next: usize,
-    end: usize,
+// The links only ever point into the heap, which is owned by the allocator
+unsafe impl Send for ListLink {}
+
+impl KSay for LinkedListAlloc {
+    const NAME: &'static str = "alloc";
 }
 
-pub struct BumpAlloc {
-    bump: Mutex<Option<RawBump>>,
+impl ListLink {
+    fn start(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end(&self) -> usize {
+        self.start() + self.size
+    }
 }
 
-impl BumpAlloc {
+impl LinkedListAlloc {
     const fn new() -> Self {
-        BumpAlloc {
-            bump: Mutex::new(None),
+        LinkedListAlloc {
+            head: IrqSpinLock::new(ListLink { size: 0, next: None }),
         }
     }
 
     pub fn init(&self, start: *mut u8, end: *mut u8) {
-        self.bump.lock().replace(RawBump {
-            next: start as usize,
-            end: end as usize,
-        });
+        let start = (start as usize).next_multiple_of(BLOCK_GRANULE);
+        let end = end as usize & !(BLOCK_GRANULE - 1);
+        assert!(start + BLOCK_GRANULE <= end, "heap is too small");
+
+        let mut head = self.head.lock();
+        assert!(head.next.is_none(), "Allocator is already initalized");
+
+        unsafe {
+            let block = start as *mut ListLink;
+            block.write(ListLink { size: end - start, next: None });
+            head.next = Some(NonNull::new_unchecked(block));
+        }
     }
 
-    pub fn alloc_page(&self) -> *mut u8 {
-        unsafe { self.alloc_zeroed(Layout::new::<PageTable>()) }
+    /// Size and alignment a request actually occupies on the heap
+    fn block_layout(layout: Layout) -> (usize, usize) {
+        let size = layout.size().max(BLOCK_GRANULE).next_multiple_of(BLOCK_GRANULE);
+        let align = layout.align().max(BLOCK_GRANULE);
+        (size, align)
     }
-}
 
-unsafe impl GlobalAlloc for BumpAlloc {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let mut lock = self.bump.lock();
+    pub fn try_alloc(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        let (size, align) = Self::block_layout(layout);
+
+        let mut head = self.head.lock();
+        let mut prev: *mut ListLink = &mut *head;
+
+        unsafe {
+            while let Some(mut curr) = (*prev).next {
+                let block = curr.as_mut();
+
+                let alloc_start = block.start().next_multiple_of(align);
+                let alloc_end = match alloc_start.checked_add(size) {
+                    Some(end) if end <= block.end() => end,
+                    _ => {
+                        prev = block;
+                        continue;
+                    }
+                };
+
+                // Both are multiples of `BLOCK_GRANULE`
+                let front = alloc_start - block.start();
+                let back = block.end() - alloc_end;
+
+                let after = if back > 0 {
+                    let rest = alloc_end as *mut ListLink;
+                    rest.write(ListLink { size: back, next: block.next });
+                    Some(NonNull::new_unchecked(rest))
+                } else {
+                    block.next
+                };
+
+                if front > 0 {
+                    // Keep the unused front of the block on the list
+                    block.size = front;
+                    block.next = after;
+                } else {
+                    (*prev).next = after;
+                }
+
+                return Ok(NonNull::new_unchecked(alloc_start as *mut u8));
+            }
+        }
+
+        Err(AllocError::OutOfMemory)
+    }
 
-        let raw_bump = lock.as_mut().expect("Allocator is uninitalized");
+    /// # Safety
+    /// `ptr` must have been returned by [`LinkedListAlloc::try_alloc`] with the same `layout`
+    pub unsafe fn free(&self, ptr: NonNull<u8>, layout: Layout) {
+        let (size, _) = Self::block_layout(layout);
+        let addr = ptr.as_ptr() as usize;
+
+        let mut head = self.head.lock();
+        let mut prev: *mut ListLink = &mut *head;
+
+        unsafe {
+            // Find the last free block before `addr`
+            while let Some(next) = (*prev).next && next.as_ref().start() < addr {
+                prev = next.as_ptr();
+            }
+
+            let block = addr as *mut ListLink;
+            block.write(ListLink { size, next: (*prev).next });
+
+            // Merge with the following block
+            if let Some(next) = (*block).next && (*block).end() == next.as_ref().start() {
+                (*block).size += next.as_ref().size;
+                (*block).next = next.as_ref().next;
+            }
+
+            // Merge into the preceding block, the sentinel never merges
+            if (*prev).size != 0 && (*prev).end() == addr {
+                (*prev).size += (*block).size;
+                (*prev).next = (*block).next;
+            } else {
+                (*prev).next = Some(NonNull::new_unchecked(block));
+            }
+        }
+    }
 
-        let addr = (raw_bump.next as usize).next_multiple_of(layout.align());
-        assert!(addr.saturating_add(layout.size()) <= raw_bump.end, "out of memory");
+    /// Returns `(free bytes, free blocks, largest free block)`
+    pub fn stats(&self) -> (usize, usize, usize) {
+        let head = self.head.lock();
+        let mut curr = head.next;
+        let (mut free, mut blocks, mut largest) = (0, 0, 0);
+
+        while let Some(block) = curr {
+            let block = unsafe { block.as_ref() };
+            free += block.size;
+            blocks += 1;
+            largest = largest.max(block.size);
+            curr = block.next;
+        }
 
-        raw_bump.next = addr + layout.size();
+        (free, blocks, largest)
+    }
+}
 
-        addr as *mut u8
+unsafe impl GlobalAlloc for LinkedListAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.try_alloc(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(err) => {
+                let (free, blocks, largest) = self.stats();
+                <Self as KSay>::kprint(format_args!(
+                    "{err:?}: requested {} bytes (align {}), {free} bytes free in {blocks} blocks, largest {largest}",
+                    layout.size(),
+                    layout.align(),
+                ));
+                core::ptr::null_mut()
+            }
+        }
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(ptr) = NonNull::new(ptr) {
+            unsafe { self.free(ptr, layout) }
+        }
     }
 }
@@ -0,0 +1,177 @@
+//! Address space identifiers
+//!
+//! Each process keeps the ASID it was last given, tagged with the generation it came from.
+//! When the ASID space runs out the generation is bumped and the whole TLB is flushed, which
+//! invalidates every handed out ASID at once. Processes holding an ASID from an old generation
+//! simply get a new one the next time they are switched to.
+//!
+//! Flushes only reach the hart doing them. A hart can hold stale entries for an ASID that was
+//! since handed to another process, or for an address space that changed while it ran
+//! elsewhere, so a process moving to a different hart always gets its ASID flushed there.
+
+use core::arch::asm;
+
+use ralloc::{vec, vec::Vec};
+use utils::sync::TicketLock;
+
+use crate::{
+    paging::{PAGE_SIZE, PageTable, SATP_ASID, SATP_SV39_ENABLE},
+    read_csr,
+    traits::KSay,
+    write_csr,
+};
+
+pub static ASIDS: TicketLock<AsidAlloc> = TicketLock::new(AsidAlloc::new());
+
+/// Widest ASID Sv39 allows
+const ASID_MAX_BITS: usize = 16;
+const BITS: usize = u64::BITS as usize;
+
+pub struct AsidAlloc {
+    /// Implemented ASID bits, 0 means every address space runs on ASID 0
+    bits: usize,
+    generation: usize,
+    bitmap: Vec<u64>,
+    next: usize,
+}
+
+impl KSay for AsidAlloc {
+    const NAME: &'static str = "asid";
+}
+
+/// Flushes every TLB entry tagged with `asid` (except global ones)
+pub fn flush_asid(asid: usize) {
+    unsafe { asm!("sfence.vma zero, {}", in(reg) asid) }
+}
+
+/// Flushes the whole TLB
+pub fn flush_all() {
+    unsafe { asm!("sfence.vma") }
+}
+
+/// Writes all ones to `satp.ASID` and reads back which bits stuck. Must be running on a
+/// valid page table, it's written back unchanged afterwards.
+pub fn detect_asid_bits() -> usize {
+    let satp = read_csr!("satp");
+    write_csr!("satp", satp | (((1 << ASID_MAX_BITS) - 1) << SATP_ASID));
+    let probed = read_csr!("satp");
+    write_csr!("satp", satp);
+    flush_all();
+
+    ((probed >> SATP_ASID) & ((1 << ASID_MAX_BITS) - 1)).count_ones() as usize
+}
+
+impl AsidAlloc {
+    const fn new() -> Self {
+        AsidAlloc {
+            bits: 0,
+            // Generation 0 is never handed out, so a zeroed process has no ASID
+            generation: 1,
+            bitmap: Vec::new(),
+            next: 1,
+        }
+    }
+
+    pub fn init(&mut self, bits: usize) {
+        self.bits = bits;
+        self.bitmap = vec![0; (1usize << bits).div_ceil(BITS)];
+        self.reserve_kernel();
+
+        <Self as KSay>::kprint(format_args!("{bits} bit ASIDs, {} usable", (1usize << bits) - 1));
+    }
+
+    /// ASID 0 belongs to the kernel page table
+    fn reserve_kernel(&mut self) {
+        if let Some(word) = self.bitmap.first_mut() {
+            *word |= 1;
+        }
+    }
+
+    fn count(&self) -> usize {
+        1 << self.bits
+    }
+
+    fn index(&self, asid: usize) -> usize {
+        asid & (self.count() - 1)
+    }
+
+    fn generation_of(&self, asid: usize) -> usize {
+        asid >> self.bits
+    }
+
+    fn is_set(&self, index: usize) -> bool {
+        self.bitmap[index / BITS] & (1 << (index % BITS)) != 0
+    }
+
+    fn find_free(&mut self) -> Option<usize> {
+        let count = self.count();
+        let index = (0..count)
+            .map(|i| (self.next + i) % count)
+            .find(|&index| !self.is_set(index))?;
+
+        self.bitmap[index / BITS] |= 1 << (index % BITS);
+        self.next = index + 1;
+        Some(index)
+    }
+
+    /// Makes sure `asid` is valid in the current generation. Returns whether it had to be
+    /// reassigned, in which case stale TLB entries for it may exist.
+    fn refresh(&mut self, asid: &mut usize) -> bool {
+        if self.bits == 0 {
+            return true;
+        }
+
+        if self.generation_of(*asid) == self.generation {
+            return false;
+        }
+
+        let index = match self.find_free() {
+            Some(index) => index,
+            None => {
+                // Every live ASID is now from an old generation, so none of them
+                // can be trusted in the TLB anymore
+                self.generation += 1;
+                self.bitmap.fill(0);
+                self.reserve_kernel();
+                flush_all();
+                self.find_free().expect("no ASIDs after rollover")
+            }
+        };
+
+        *asid = (self.generation << self.bits) | index;
+        true
+    }
+
+    /// Releases `asid` if it's from the current generation
+    pub fn free(&mut self, asid: usize) {
+        if self.bits == 0 || self.generation_of(asid) != self.generation {
+            return;
+        }
+
+        let index = self.index(asid);
+        if index != 0 {
+            self.bitmap[index / BITS] &= !(1 << (index % BITS));
+        }
+    }
+
+    /// Switches `satp` to `page_table`, (re)assigning `asid` if needed. Only flushes the TLB
+    /// when the ASID is new to this address space, or `migrated` says it last ran on another
+    /// hart.
+    pub fn switch_to(&mut self, asid: &mut usize, page_table: *mut PageTable, migrated: bool) {
+        let fresh = self.refresh(asid) || migrated;
+        let index = self.index(*asid);
+
+        write_csr!(
+            "satp",
+            SATP_SV39_ENABLE | (index << SATP_ASID) | (page_table as usize / PAGE_SIZE)
+        );
+
+        if fresh {
+            if self.bits == 0 {
+                flush_all();
+            } else {
+                flush_asid(index);
+            }
+        }
+    }
+}
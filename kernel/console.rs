@@ -0,0 +1,71 @@
+//! Console input
+//!
+//! The UART interrupts whenever a byte arrives, the handler moves everything it has into a
+//! buffer and wakes whoever is waiting in [`getchar`]. Without a PLIC routed interrupt this
+//! falls back to polling the SBI console.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use ralloc::collections::VecDeque;
+
+use crate::{dtree::DeviceTree, lock::IrqSpinLock, plic, proc::r#yield, sbi::sbi_getchar, traits::KSay, uart::UART16550, wait::WaitQueue};
+
+/// Bytes nobody read yet, anything past this is dropped
+const INPUT_MAX: usize = 256;
+
+static INPUT: IrqSpinLock<VecDeque<u8>> = IrqSpinLock::new(VecDeque::new());
+static INPUT_WAIT: WaitQueue = WaitQueue::new();
+static IRQ_DRIVEN: AtomicBool = AtomicBool::new(false);
+
+pub struct Console;
+
+impl KSay for Console {
+    const NAME: &'static str = "console";
+}
+
+/// Hooks up the UART receive interrupt, the UART has to be initalized already
+pub fn init(dtree: &DeviceTree) {
+    let irq = dtree.search("/soc/serial").and_then(|node| node.irq());
+    let (Some(irq), Some(uart)) = (irq, UART16550.get()) else {
+        Console::kprint("no console interrupt, polling for input");
+        return;
+    };
+
+    if plic::register(irq, handle_irq) {
+        uart.lock().enable_rx_interrupt();
+        IRQ_DRIVEN.store(true, Ordering::Relaxed);
+        Console::kprint(format_args!("input on interrupt {irq}"));
+    }
+}
+
+fn handle_irq() {
+    let Some(uart) = UART16550.get() else {
+        return;
+    };
+
+    {
+        let mut uart = uart.lock();
+        let mut input = INPUT.lock();
+        while let Some(byte) = uart.read_byte() {
+            if input.len() < INPUT_MAX {
+                input.push_back(byte);
+            }
+        }
+    }
+
+    INPUT_WAIT.wake_all();
+}
+
+/// Blocks until a byte of input is available
+pub fn getchar() -> u8 {
+    if IRQ_DRIVEN.load(Ordering::Relaxed) {
+        return INPUT_WAIT.wait_until(|| INPUT.lock().pop_front());
+    }
+
+    loop {
+        if let Ok(char) = sbi_getchar() {
+            return char;
+        }
+        r#yield();
+    }
+}
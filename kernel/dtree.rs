@@ -58,6 +58,8 @@ pub struct DeviceTreeHeader {
 
 #[derive(Debug, Clone)]
 pub struct DeviceTree {
+    /// `(start, end)` of the blob itself, every name and value points into it
+    blob: (usize, usize),
     node_list_root: DeviceTreeNode,
     // Sighs... I get why they use linked lists now.
     // I've been defeated. I'll change this to a linked list at some point.
@@ -137,6 +139,12 @@ impl MemResBlock {
     fn is_zero(&self) -> bool {
         self.addr.is_null() && self.size == 0
     }
+
+    /// The block is stored big-endian, straight out of the blob
+    pub fn range(&self) -> (usize, usize) {
+        let addr = usize::from_be(self.addr as usize);
+        (addr, addr + usize::from_be(self.size))
+    }
 }
 
 #[repr(C)]
@@ -148,6 +156,7 @@ pub struct PropData {
 }
 
 struct DeviceTreeParser {
+    blob: (usize, usize),
     dt_struct: *const u8,
     dt_struct_size: usize,
     dt_struct_slice: &'static [u8],
@@ -176,7 +185,9 @@ impl DeviceTreeParser {
             let resrvd_mem =
                 header_ptr.add(u32::from_be(header.off_mem_resvmap) as usize) as *const MemResBlock;
 
+            let start = header_ptr as usize;
             DeviceTreeParser {
+                blob: (start, start + u32::from_be(header.totalsize) as usize),
                 dt_struct,
                 dt_struct_size,
                 dt_struct_slice,
@@ -191,6 +202,7 @@ impl DeviceTreeParser {
         let node_list_root = self.parse_nodes().into_iter().next().unwrap();
 
         DeviceTree {
+            blob: self.blob,
             node_list_root,
             resvd_mem,
         }
@@ -359,7 +371,7 @@ impl DeviceTreeParser {
 }
 
 impl DeviceTreeNode {
-    fn addr_size_cells(&self) -> Option<(u32, u32)> {
+    pub fn addr_size_cells(&self) -> Option<(u32, u32)> {
         let props = &self.properties;
         let addr_cells = props.iter().find_map(|elem| {
             if elem.name.contains("#address-cells") {
@@ -381,8 +393,48 @@ impl DeviceTreeNode {
         }
     }
 
-    fn find_prop(&self, name: &str) -> Option<&DeviceTreeNode> {
-        todo!()
+    pub fn find_prop(&self, name: &str) -> Option<&DeviceTreeProperty> {
+        self.properties.iter().find(|prop| prop.name == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Node name without the unit address, `memory@80000000` -> `memory`
+    pub fn base_name(&self) -> &'static str {
+        self.name.split('@').next().unwrap_or(self.name)
+    }
+
+    pub fn children(&self) -> &[DeviceTreeNode] {
+        &self.child_node
+    }
+
+    /// Decodes the `reg` property into `(addr, size)` pairs, using the
+    /// `#address-cells` and `#size-cells` of the parent node.
+    pub fn reg(&self, (addr_cells, size_cells): (u32, u32)) -> Vec<(usize, usize)> {
+        let Some(prop) = self.find_prop("reg") else {
+            return Vec::new();
+        };
+
+        let cells = (addr_cells + size_cells) as usize;
+        if cells == 0 {
+            return Vec::new();
+        }
+
+        prop.value
+            .chunks_exact(cells * 4)
+            .map(|chunk| {
+                let (addr, size) = chunk.split_at(addr_cells as usize * 4);
+                (read_cells(addr), read_cells(size))
+            })
+            .collect()
+    }
+
+    /// First cell of the `interrupts` property, the interrupt source of a PLIC routed device
+    pub fn irq(&self) -> Option<u32> {
+        let cell = self.find_prop("interrupts")?.value().first_chunk::<4>()?;
+        Some(u32::from_be_bytes(*cell))
     }
 
     pub fn get_addr(&self) -> Option<&str> {
@@ -443,11 +495,74 @@ impl DeviceTreeNode {
     }
 }
 
+/// Folds big-endian 32 bit cells into a single value
+fn read_cells(cells: &[u8]) -> usize {
+    cells
+        .chunks_exact(4)
+        .fold(0, |acc, cell| (acc << 32) | u32::from_be_bytes(*cell.as_array().unwrap()) as usize)
+}
+
+impl DeviceTreeProperty {
+    pub fn value(&self) -> &'static [u8] {
+        self.value
+    }
+}
+
 impl DeviceTree {
     pub fn search(&self, path: &str) -> Option<&DeviceTreeNode> {
         self.node_list_root.search(&path[1..])
     }
 
+    pub fn root(&self) -> &DeviceTreeNode {
+        &self.node_list_root
+    }
+
+    pub fn blob_range(&self) -> (usize, usize) {
+        self.blob
+    }
+
+    pub fn reserved_mem(&self) -> &[MemResBlock] {
+        &self.resvd_mem
+    }
+
+    /// All `(start, end)` ranges described by `/memory` nodes.
+    /// [`DeviceTree::search`] can't be used here, since `reserved-memory` also matches.
+    pub fn memory_regions(&self) -> Vec<(usize, usize)> {
+        let cells = self.node_list_root.addr_size_cells().unwrap_or((2, 1));
+        self.node_list_root
+            .child_node
+            .iter()
+            .filter(|node| node.base_name() == "memory")
+            .flat_map(|node| node.reg(cells))
+            .map(|(addr, size)| (addr, addr + size))
+            .collect()
+    }
+
+    /// Ranges from both the memory reservation block and the `/reserved-memory` node
+    pub fn reserved_regions(&self) -> Vec<(usize, usize)> {
+        let mut regions: Vec<(usize, usize)> = self.resvd_mem.iter().map(MemResBlock::range).collect();
+
+        if let Some(node) = self.node_list_root.child_node.iter().find(|node| node.base_name() == "reserved-memory") {
+            let cells = node.addr_size_cells().unwrap_or((2, 1));
+            for child in node.child_node.iter() {
+                regions.extend(child.reg(cells).into_iter().map(|(addr, size)| (addr, addr + size)));
+            }
+        }
+
+        regions
+    }
+
+    /// Value of `key=value` in `/chosen/bootargs`, `Some("")` for a bare `key`
+    pub fn bootarg(&self, key: &str) -> Option<&'static str> {
+        let args = self.search("/chosen")?.find_prop("bootargs")?.value();
+        let args = str::from_utf8(args).ok()?.trim_end_matches('\0');
+
+        args.split_ascii_whitespace().find_map(|arg| match arg.split_once('=') {
+            Some((name, value)) => (name == key).then_some(value),
+            None => (arg == key).then_some(""),
+        })
+    }
+
     pub fn print_properties(&self) {
         let cells = self.node_list_root.addr_size_cells();
         self.node_list_root.print(1, cells.unwrap());
@@ -1 +1,305 @@
-struct Elf {}
+//! ELF64 loader for RISC-V executables
+//!
+//! Only statically linked `ET_EXEC` images are supported. Every `PT_LOAD` segment gets its own
+//! [`Vma`] with the segment's permissions, the part of a segment past its file data (bss) is a
+//! separate anonymous area that the page fault handler zero fills. A page shared by two segments
+//! becomes an area of its own with the permissions of both.
+
+use core::ptr;
+
+use ralloc::vec::Vec;
+
+use crate::{
+    paging::{PAGE_R, PAGE_SIZE, PAGE_W, PAGE_X, PageTable, PagingError},
+    user::{USER_BASE, USER_END},
+    vma::{Backing, Vma, VmaError, VmaKind, VmaList},
+};
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+
+const EI_CLASS: usize = 4;
+const EI_DATA: usize = 5;
+const EI_VERSION: usize = 6;
+
+const PT_LOAD: u32 = 1;
+/// Largest program header table accepted, it's copied onto the initial stack
+const MAX_PHDR_BYTES: usize = PAGE_SIZE;
+
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ElfHeader {
+    pub e_ident: [u8; 16],
+    pub e_type: u16,
+    pub e_machine: u16,
+    pub e_version: u32,
+    pub e_entry: u64,
+    pub e_phoff: u64,
+    pub e_shoff: u64,
+    pub e_flags: u32,
+    pub e_ehsize: u16,
+    pub e_phentsize: u16,
+    pub e_phnum: u16,
+    pub e_shentsize: u16,
+    pub e_shnum: u16,
+    pub e_shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub p_flags: u32,
+    pub p_offset: u64,
+    pub p_vaddr: u64,
+    pub p_paddr: u64,
+    pub p_filesz: u64,
+    pub p_memsz: u64,
+    pub p_align: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// Image is smaller than the headers say it is
+    Truncated,
+    BadMagic,
+    NotElf64,
+    NotLittleEndian,
+    BadVersion,
+    /// Not an `ET_EXEC`
+    NotExecutable,
+    NotRiscV,
+    BadProgramHeader,
+    /// Segment is outside of user space, or has more file data than memory
+    BadSegment,
+    /// Entry point isn't in an executable segment
+    BadEntry,
+    Paging(PagingError),
+    Vma(VmaError),
+}
+
+impl From<PagingError> for ElfError {
+    fn from(err: PagingError) -> Self {
+        ElfError::Paging(err)
+    }
+}
+
+impl From<VmaError> for ElfError {
+    fn from(err: VmaError) -> Self {
+        ElfError::Vma(err)
+    }
+}
+
+pub struct Elf<'a> {
+    data: &'a [u8],
+    header: ElfHeader,
+}
+
+/// Reads a `T` at `offset`, the image has no alignment guarantees
+fn read_at<T: Copy>(data: &[u8], offset: usize) -> Result<T, ElfError> {
+    match offset.checked_add(size_of::<T>()) {
+        Some(end) if end <= data.len() => Ok(unsafe { ptr::read_unaligned(data.as_ptr().add(offset).cast()) }),
+        _ => Err(ElfError::Truncated),
+    }
+}
+
+/// Adds `area` above the last of `areas`. Segments can share a page at their boundary, which
+/// is split off into an area of its own with the permissions of both.
+fn push_area(areas: &mut Vec<Vma>, mut area: Vma) {
+    if let Some(last) = areas.last_mut()
+        && last.end > area.start
+    {
+        // Segments don't overlap, so only the last page of `last` can be shared
+        let page = last.end - PAGE_SIZE;
+        let (kind, backing) = match last.backing {
+            Backing::Anon => (area.kind, area.backing),
+            Backing::Image { .. } => (last.kind, last.backing),
+        };
+        let shared = Vma::new(page, last.end, last.flags | area.flags, kind, backing);
+
+        last.end = page;
+        if last.start == last.end {
+            areas.pop();
+        }
+        areas.push(shared);
+
+        area.start = shared.end;
+        if area.start == area.end {
+            return;
+        }
+    }
+
+    areas.push(area);
+}
+
+impl ProgramHeader {
+    fn page_flags(&self) -> usize {
+        let mut flags = 0;
+        if self.p_flags & PF_R != 0 {
+            flags |= PAGE_R;
+        }
+        // Sv39 reserves write only mappings
+        if self.p_flags & PF_W != 0 {
+            flags |= PAGE_R | PAGE_W;
+        }
+        if self.p_flags & PF_X != 0 {
+            flags |= PAGE_X;
+        }
+        flags
+    }
+
+    fn kind(&self) -> VmaKind {
+        if self.p_flags & PF_X != 0 {
+            VmaKind::Text
+        } else if self.p_flags & PF_W != 0 {
+            VmaKind::Data
+        } else {
+            VmaKind::Rodata
+        }
+    }
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        let header: ElfHeader = read_at(data, 0)?;
+        let ident = &header.e_ident;
+
+        if ident[..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if ident[EI_CLASS] != ELFCLASS64 {
+            return Err(ElfError::NotElf64);
+        }
+        if ident[EI_DATA] != ELFDATA2LSB {
+            return Err(ElfError::NotLittleEndian);
+        }
+        if ident[EI_VERSION] != EV_CURRENT || header.e_version != EV_CURRENT as u32 {
+            return Err(ElfError::BadVersion);
+        }
+        if header.e_type != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if header.e_machine != EM_RISCV {
+            return Err(ElfError::NotRiscV);
+        }
+        if header.e_phnum > 0 && (header.e_phentsize as usize) < size_of::<ProgramHeader>() {
+            return Err(ElfError::BadProgramHeader);
+        }
+        if header.e_phentsize as usize * header.e_phnum as usize > MAX_PHDR_BYTES {
+            return Err(ElfError::BadProgramHeader);
+        }
+
+        let elf = Elf { data, header };
+        // Make sure the whole table, every program header in it, is actually there
+        elf.program_header_bytes()?;
+
+        Ok(elf)
+    }
+
+    pub fn entry(&self) -> usize {
+        self.header.e_entry as usize
+    }
+
+    /// Size of a program header entry
+    pub fn phent(&self) -> usize {
+        self.header.e_phentsize as usize
+    }
+
+    pub fn phnum(&self) -> usize {
+        self.header.e_phnum as usize
+    }
+
+    /// The raw program header table
+    pub fn program_header_bytes(&self) -> Result<&'a [u8], ElfError> {
+        let start = usize::try_from(self.header.e_phoff).map_err(|_| ElfError::Truncated)?;
+        let end = self
+            .phent()
+            .checked_mul(self.phnum())
+            .and_then(|len| start.checked_add(len))
+            .ok_or(ElfError::Truncated)?;
+        self.data.get(start..end).ok_or(ElfError::Truncated)
+    }
+
+    fn program_header(&self, index: usize) -> Result<ProgramHeader, ElfError> {
+        let offset = index
+            .checked_mul(self.phent())
+            .and_then(|offset| offset.checked_add(usize::try_from(self.header.e_phoff).ok()?))
+            .ok_or(ElfError::Truncated)?;
+        read_at(self.data, offset)
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> {
+        (0..self.header.e_phnum as usize).filter_map(|index| self.program_header(index).ok())
+    }
+
+    /// Maps every `PT_LOAD` segment into `table` and records it in `vmas`.
+    /// Returns the end of the highest segment.
+    pub fn load(&self, table: &mut PageTable, vmas: &mut VmaList) -> Result<usize, ElfError> {
+        let mut image_end = 0;
+        let mut entry_ok = false;
+
+        let mut segments: Vec<ProgramHeader> =
+            self.program_headers().filter(|ph| ph.p_type == PT_LOAD && ph.p_memsz > 0).collect();
+        segments.sort_unstable_by_key(|ph| ph.p_vaddr);
+
+        let mut areas: Vec<Vma> = Vec::new();
+        let mut prev_end = 0;
+        for ph in &segments {
+            let (vaddr, memsz, filesz) = (ph.p_vaddr as usize, ph.p_memsz as usize, ph.p_filesz as usize);
+            let offset = ph.p_offset as usize;
+
+            let in_user = vaddr >= USER_BASE && vaddr.checked_add(memsz).is_some_and(|end| end <= USER_END);
+            let in_file = offset.checked_add(filesz).is_some_and(|end| end <= self.data.len());
+            if !in_user || !in_file || filesz > memsz || vaddr < prev_end {
+                return Err(ElfError::BadSegment);
+            }
+            prev_end = vaddr + memsz;
+
+            let start = vaddr & !(PAGE_SIZE - 1);
+            let end = (vaddr + memsz).next_multiple_of(PAGE_SIZE);
+            // Pages holding any file data come from the image, whole pages past it are bss
+            let file_end = if filesz > 0 { (vaddr + filesz).next_multiple_of(PAGE_SIZE) } else { start };
+            let flags = ph.page_flags();
+
+            if file_end > start {
+                push_area(&mut areas, Vma::new(start, file_end, flags, ph.kind(), Backing::Image { offset }));
+            }
+            if end > file_end {
+                push_area(&mut areas, Vma::new(file_end, end, flags, VmaKind::Bss, Backing::Anon));
+            }
+
+            entry_ok |= flags & PAGE_X != 0 && (vaddr..vaddr + memsz).contains(&self.entry());
+            image_end = image_end.max(end);
+        }
+
+        for area in areas {
+            vmas.insert(area)?;
+        }
+
+        // Copied once every area is in place, a segment's data can span a shared page's area
+        for ph in segments.iter().filter(|ph| ph.p_filesz > 0) {
+            let (vaddr, offset) = (ph.p_vaddr as usize, ph.p_offset as usize);
+            let data = &self.data[offset..offset + ph.p_filesz as usize];
+
+            for area in vmas.iter().filter(|area| area.start < vaddr + data.len() && vaddr < area.end) {
+                let from = vaddr.max(area.start);
+                let to = (vaddr + data.len()).min(area.end);
+                area.populate(table, from - area.start, &data[from - vaddr..to - vaddr])?;
+            }
+        }
+
+        if !entry_ok {
+            return Err(ElfError::BadEntry);
+        }
+
+        Ok(image_end)
+    }
+}
@@ -8,16 +8,32 @@
 //! to the shell through a syscall and all is well. The shell can then request to change location or
 //! read a file. I'm working on writing a file. It's in progress mentally. Just not physically.
 
-use core::{alloc::Layout, fmt::{Binary, Debug}, mem::offset_of, slice};
+use core::{fmt::{Binary, Debug}, mem::offset_of};
 
-use ralloc::{alloc, vec::Vec};
-use spin::once::Once;
+use ralloc::{vec, vec::Vec};
+use utils::sync::Once;
 
 use crate::{traits::KSay, virtio::{SECTOR_SIZE, read_disk, write_disk}};
 
 const ROOT_INODE: u32 = 2;
-
-pub static mut BLOCK_SZ_BUF: Once<&mut [u8]> = Once::new();
+/// Direct block pointers in an inode
+const DIRECT_BLOCKS: usize = 12;
+/// Largest file read in one go, everything read is held in the kernel heap
+pub const MAX_FILE_SIZE: usize = 16 * 1024 * 1024;
+
+/// The mounted volume
+pub static FS: Once<Ext2> = Once::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    NotAFile,
+    /// Path isn't absolute
+    BadPath,
+    /// File is larger than [`MAX_FILE_SIZE`]
+    TooLarge,
+}
 
 pub fn init() {
     let mut buf = [0u8; 1024];
@@ -26,26 +42,46 @@ pub fn init() {
     read_disk(&mut buf[512..], 3);
 
     let superblock: &Superblock = unsafe { &core::mem::transmute(buf) };
-    let fs: Ext2 = superblock.get_ext2();
+    let fs: &Ext2 = FS.call_once(|| superblock.get_ext2());
     <Ext2 as KSay>::kprint("Ext2 initalized");
 
-    unsafe {
-        BLOCK_SZ_BUF.call_once(|| {
-            let size = fs.blck_size as usize;
-            let ptr = alloc::alloc(Layout::from_size_align_unchecked(size, size));
-            slice::from_raw_parts_mut(ptr, size)
-        })
-    };
-
-    fs.read_block(unsafe { BLOCK_SZ_BUF.get_mut_unchecked() }, 1);
+    // Reading sleeps, so no shared buffer (and no lock) for this
+    let mut block = vec![0u8; fs.blck_size as usize];
+    fs.read_block(&mut block, 1);
 
     let root_inode = fs.read_inode(ROOT_INODE);
-    let block_0 = root_inode.direct_block_ptr[0];
-    fs.read_block(unsafe { BLOCK_SZ_BUF.get_mut_unchecked() }, block_0 as usize);
-    unsafe {
-        let block = BLOCK_SZ_BUF.get_unchecked();
-        let ents = fs.parse_dir_entries(block);
-        println!("{:#?}", ents);
+    match fs.read_file(&root_inode) {
+        Ok(root) => {
+            println!("{:#?}", fs.parse_dir_entries(&root));
+        }
+        Err(err) => <Ext2 as KSay>::kprint(format_args!("can't read the root directory: {err:?}")),
+    }
+}
+
+/// The last pointer block read at each level of indirection, 0 is the one pointing at data.
+/// Files are read in order, so almost every lookup hits.
+struct PtrBlocks {
+    cached: [(u32, Vec<u8>); 3],
+}
+
+impl PtrBlocks {
+    fn new() -> Self {
+        PtrBlocks { cached: [(0, Vec::new()), (0, Vec::new()), (0, Vec::new())] }
+    }
+
+    /// Entry `index` of pointer `block` at `level`, 0 for holes
+    fn read(&mut self, fs: &Ext2, level: usize, block: u32, index: usize) -> u32 {
+        if block == 0 {
+            return 0;
+        }
+
+        let (cached, buf) = &mut self.cached[level];
+        if *cached != block || buf.is_empty() {
+            buf.resize(fs.blck_size as usize, 0);
+            fs.read_block(buf, block as usize);
+            *cached = block;
+        }
+        u32::from_le_bytes(buf[index * 4..index * 4 + 4].try_into().unwrap())
     }
 }
 
@@ -164,55 +200,134 @@ impl Ext2 {
         }
     }
 
+    /// Walks the entries of a directory's data by their record length. Unused entries (inode 0) are skipped
     fn parse_dir_entries<'a>(&self, buf: &'a [u8]) -> Vec<ParsedDirEntry<'a>> {
+        let name_offset = offset_of!(DirEntry, name_first_byte);
         let mut curr = 0;
-        let mut curr_dir_ent = unsafe { buf.as_ptr().cast::<DirEntry>().read() };
         let mut vec = Vec::with_capacity(12);
-        while curr_dir_ent.inode != 0 {
-            let DirEntry { inode, size, name_len_lsb: name_len, name_len_msb_or_ty_ind: dir_ty, name_first_byte: _ } = curr_dir_ent;
-            curr += offset_of!(DirEntry, name_first_byte);
-            // # Safety:
-            let name_start = unsafe { buf.as_ptr().add(curr) };
-            let name = unsafe { slice::from_raw_parts(name_start, name_len as usize) };
-            vec.push(ParsedDirEntry { inode, size, dir_ty, name });
-            curr += name_len as usize - 1;
-            // # Safety: We manually align the pointer before the cast
-            unsafe {
-                curr += 4 - (curr % 4);
-                let ptr = buf.as_ptr().add(curr).cast::<DirEntry>();
-                curr_dir_ent = ptr.read();
+
+        while curr + name_offset <= buf.len() {
+            let DirEntry { inode, size, name_len_lsb: name_len, name_len_msb_or_ty_ind: dir_ty, name_first_byte: _ } =
+                unsafe { buf.as_ptr().add(curr).cast::<DirEntry>().read_unaligned() };
+
+            // A corrupt record length would loop forever or run off the block
+            let name_end = curr + name_offset + name_len as usize;
+            if (size as usize) < name_offset || name_end > buf.len() {
+                break;
+            }
+
+            if inode != 0 {
+                vec.push(ParsedDirEntry { inode, size, dir_ty, name: &buf[curr + name_offset..name_end] });
             }
+            curr += size as usize;
         }
         vec
     }
 
+    fn group_descriptor(&self, group: u32) -> BlockGroupDescriptor {
+        // The descriptor table starts in the block after the superblock, which is block 1 for 1KiB blocks
+        let table = if self.blck_size == 1024 { 2 } else { 1 };
+        let per_block = self.blck_size as usize / size_of::<BlockGroupDescriptor>();
+
+        let mut buf = vec![0u8; self.blck_size as usize];
+        self.read_block(&mut buf, table + group as usize / per_block);
+        unsafe { buf.as_ptr().cast::<BlockGroupDescriptor>().add(group as usize % per_block).read_unaligned() }
+    }
+
     fn read_inode(&self, inode: u32) -> Inode {
         let block_group = (inode - 1) / self.inode_per_bg;
-        
-        let bgdesc: &[BlockGroupDescriptor] = unsafe {
-            let ptr = BLOCK_SZ_BUF.get_unchecked().as_ptr().cast::<BlockGroupDescriptor>();
-            slice::from_raw_parts(ptr, self.blck_size as usize / size_of::<BlockGroupDescriptor>())
-        };
-
-        let bg = bgdesc[block_group as usize];
+        let bg = self.group_descriptor(block_group);
 
         // INODE ADDRESSES START AT 1
         // Root Inode always 2
         let index = (inode - 1) % self.inode_per_bg;
+        let offset = index as usize * self.inode_size as usize;
+
+        let mut buf = vec![0u8; self.blck_size as usize];
+        self.read_block(&mut buf, bg.block_addr_inode_table as usize + offset / self.blck_size as usize);
+
+        unsafe { buf.as_ptr().add(offset % self.blck_size as usize).cast::<Inode>().read_unaligned() }
+    }
+
+    /// Block number holding the `index`th block of the inode's data, 0 for holes
+    fn data_block(&self, inode: &Inode, index: usize, ptrs: &mut PtrBlocks) -> u32 {
+        let per_block = self.blck_size as usize / size_of::<u32>();
+
+        if index < DIRECT_BLOCKS {
+            return inode.direct_block_ptr[index];
+        }
+
+        let index = index - DIRECT_BLOCKS;
+        if index < per_block {
+            return ptrs.read(self, 0, inode.single_indirect_blk_ptr, index);
+        }
+
+        let index = index - per_block;
+        if index < per_block * per_block {
+            let single = ptrs.read(self, 1, inode.doubly_indirect_blk_ptr, index / per_block);
+            return ptrs.read(self, 0, single, index % per_block);
+        }
+
+        let index = index - per_block * per_block;
+        let double = ptrs.read(self, 2, inode.triply_indirect_blk_ptr, index / (per_block * per_block));
+        let single = ptrs.read(self, 1, double, (index / per_block) % per_block);
+        ptrs.read(self, 0, single, index % per_block)
+    }
+
+    /// Reads the whole contents of an inode
+    fn read_file(&self, inode: &Inode) -> Result<Vec<u8>, FsError> {
+        let size = inode.size_lb as usize;
+        if size > MAX_FILE_SIZE {
+            return Err(FsError::TooLarge);
+        }
 
-        self.read_block(unsafe { BLOCK_SZ_BUF.get_mut_unchecked() }, bg.block_addr_inode_table as usize);
+        let blck_size = self.blck_size as usize;
+        let mut data = vec![0u8; size.next_multiple_of(blck_size)];
+        let mut ptrs = PtrBlocks::new();
 
-        // Some work may need to be done to ensure that since the block table *starting address* is what we get, that we
-        // index into the proper block to get our inode. I think just div and mod to address
-        // Since the containing block number isn't used in accessing the data, we ignore this
-        let containing_block = ((index as u32 * self.inode_size as u32) / self.blck_size) + 1;
+        for (index, chunk) in data.chunks_mut(blck_size).enumerate() {
+            match self.data_block(inode, index, &mut ptrs) {
+                0 => {} // Sparse, already zeroed
+                block => self.read_block(chunk, block as usize),
+            }
+        }
+
+        data.truncate(size);
+        Ok(data)
+    }
+
+    /// Finds the inode number of an absolute path
+    pub fn lookup(&self, path: &[u8]) -> Result<u32, FsError> {
+        if path.first() != Some(&b'/') {
+            return Err(FsError::BadPath);
+        }
 
-        let inode = unsafe {
-            let block_table = BLOCK_SZ_BUF.get_mut_unchecked().as_ptr().add(index as usize * self.inode_size as usize).cast::<Inode>();
-            block_table.read()
-        };
+        let mut curr = ROOT_INODE;
+        for name in path.split(|&c| c == b'/').filter(|name| !name.is_empty()) {
+            let inode = self.read_inode(curr);
+            if inode.ty_perm & 0xF000 != InodeTyPerms::DIR {
+                return Err(FsError::NotADirectory);
+            }
+
+            let data = self.read_file(&inode)?;
+            curr = self
+                .parse_dir_entries(&data)
+                .iter()
+                .find(|entry| entry.name == name)
+                .ok_or(FsError::NotFound)?
+                .inode;
+        }
 
-        inode
+        Ok(curr)
+    }
+
+    /// Reads the regular file at an absolute path
+    pub fn read_path(&self, path: &[u8]) -> Result<Vec<u8>, FsError> {
+        let inode = self.read_inode(self.lookup(path)?);
+        if inode.ty_perm & 0xF000 != InodeTyPerms::FILE {
+            return Err(FsError::NotAFile);
+        }
+        self.read_file(&inode)
     }
 }
 
@@ -272,7 +387,7 @@ struct Inode {
     num_disk_sectors: u32,        // Count of disk sectors (not Ext2 blocks) in use by this inode, not counting the actual inode structure nor directory entries linking to the inode.
     flags: u32,                   // Flags (see below)
     os_val_1: u32,                // Operating System Specific value #1
-    direct_block_ptr: [u32; 11],      // Direct Block Pointer 0-10
+    direct_block_ptr: [u32; 12],  // Direct Block Pointer 0-11
     single_indirect_blk_ptr: u32, // Singly Indirect Block Pointer (Points to a block that is a list of block pointers to data)
     doubly_indirect_blk_ptr: u32, // Doubly Indirect Block Pointer (Points to a block that is a list of block pointers to Singly Indirect Blocks)
     triply_indirect_blk_ptr: u32, // Triply Indirect Block Pointer (Points to a block that is a list of block pointers to Doubly Indirect Blocks)
@@ -0,0 +1,176 @@
+//! Page fault handling
+//!
+//! Faults on unmapped pages inside one of the current process's [`crate::vma`] areas are
+//! resolved by mapping a fresh zeroed page, faults just below the stack grow it. Stores to
+//! [`PAGE_COW`] pages get their own copy of the page. Anything else
+//! kills the process (dumping its address space) if it came from user mode, and panics if the
+//! kernel did it.
+
+use core::fmt::Display;
+
+use crate::{
+    hart::curr_proc,
+    frame::FRAME_ALLOC,
+    paging::{PAGE_COW, PAGE_R, PAGE_SIZE, PAGE_U, PAGE_W, PAGE_X, PAddr, PageTable, PagingError, VAddr, flush_page},
+    proc::{KILLED_STATUS, Process, exit_current},
+    traits::KSay,
+    trap::{SCAUSE_INST_PAGE_FAULT, SCAUSE_STORE_PAGE_FAULT, TrapFrame, scause_name},
+    user::{USER_BASE, USER_END, USER_STACK_MAX},
+};
+
+pub struct PageFault;
+
+impl KSay for PageFault {
+    const NAME: &'static str = "fault";
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Exec,
+    Load,
+    Store,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FaultError {
+    /// Address is outside of user space
+    NotUser,
+    /// No area covers the address
+    NoArea,
+    /// The area doesn't allow this kind of access
+    Protection,
+    OutOfMemory,
+    Paging(PagingError),
+}
+
+impl From<PagingError> for FaultError {
+    fn from(err: PagingError) -> Self {
+        FaultError::Paging(err)
+    }
+}
+
+impl Display for FaultError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FaultError::NotUser => write!(f, "not a user address"),
+            FaultError::NoArea => write!(f, "no mapping"),
+            FaultError::Protection => write!(f, "permission denied"),
+            FaultError::OutOfMemory => write!(f, "out of memory"),
+            FaultError::Paging(err) => write!(f, "{err:?}"),
+        }
+    }
+}
+
+impl Access {
+    fn from_scause(scause: usize) -> Access {
+        match scause {
+            SCAUSE_INST_PAGE_FAULT => Access::Exec,
+            SCAUSE_STORE_PAGE_FAULT => Access::Store,
+            _ => Access::Load,
+        }
+    }
+
+    fn allowed_by(self, flags: usize) -> bool {
+        let needed = match self {
+            Access::Exec => PAGE_X,
+            Access::Load => PAGE_R,
+            Access::Store => PAGE_W,
+        };
+        flags & needed != 0
+    }
+}
+
+pub fn handle_page_fault(f: &mut TrapFrame, scause: usize, stval: usize) {
+    let access = Access::from_scause(scause);
+
+    let result = match curr_proc() {
+        Some(proc) => resolve(unsafe { &mut *proc }, stval, access),
+        None => Err(FaultError::NotUser),
+    };
+
+    let Err(err) = result else {
+        return;
+    };
+
+    let sepc = f.sepc;
+    if !f.from_user() {
+        panic!(
+            "trap handler: {} at {:#x} (stval={:#x}): {}",
+            scause_name(scause), sepc, stval, err
+        );
+    }
+
+    <PageFault as KSay>::kprint(format_args!(
+        "killing pid {}: {} at {:#x} (stval={:#x}): {}",
+        unsafe { (*curr_proc().unwrap()).pid },
+        scause_name(scause),
+        sepc,
+        stval,
+        err
+    ));
+    unsafe {
+        let proc = &*curr_proc().unwrap();
+        proc.vmas.dump(proc.pid, &*proc.page_table);
+    }
+    exit_current(KILLED_STATUS);
+}
+
+fn resolve(proc: &mut Process, addr: usize, access: Access) -> Result<(), FaultError> {
+    if !(USER_BASE..USER_END).contains(&addr) {
+        return Err(FaultError::NotUser);
+    }
+
+    let page = addr & !(PAGE_SIZE - 1);
+    let vma = match proc.vmas.find(addr) {
+        Some(vma) => *vma,
+        None => *proc.vmas.grow_stack(page, USER_STACK_MAX).ok_or(FaultError::NoArea)?,
+    };
+
+    if !access.allowed_by(vma.flags) {
+        return Err(FaultError::Protection);
+    }
+
+    let table = unsafe { &mut *proc.page_table };
+    if let Some((frame, flags)) = table.translate(VAddr(page as *const ())) {
+        if access == Access::Store && flags & PAGE_COW != 0 {
+            return break_cow(table, page, frame.0 as *mut u8, flags);
+        }
+        // Mapped, so the fault was about the page's permissions
+        return Err(FaultError::Protection);
+    }
+
+    let frame = FRAME_ALLOC.alloc_frame().map_err(|_| FaultError::OutOfMemory)?;
+    if let Err(err) = table.map_page(
+        VAddr(page as *const ()),
+        PAddr(frame as *const ()),
+        (vma.flags & (PAGE_R | PAGE_W | PAGE_X)) | PAGE_U,
+    ) {
+        FRAME_ALLOC.free_frame(frame).expect("fault frame was just allocated");
+        return Err(err.into());
+    }
+    flush_page(page);
+
+    Ok(())
+}
+
+/// Gives `page` a private writable copy of `frame`, or just makes it writable again if no
+/// one else shares the frame anymore
+fn break_cow(table: &mut PageTable, page: usize, frame: *mut u8, flags: usize) -> Result<(), FaultError> {
+    let vaddr = VAddr(page as *const ());
+    let flags = (flags & !PAGE_COW) | PAGE_W;
+
+    if FRAME_ALLOC.owners(frame) == Ok(1) {
+        table.protect(vaddr, flags)?;
+        return Ok(());
+    }
+
+    let copy = FRAME_ALLOC.alloc_frame().map_err(|_| FaultError::OutOfMemory)?;
+    unsafe { core::ptr::copy_nonoverlapping(frame, copy, PAGE_SIZE) };
+
+    table.unmap_page(VAddr(page as *const ()))?;
+    table.map_page(vaddr, PAddr(copy as *const ()), flags)?;
+    flush_page(page);
+
+    FRAME_ALLOC.free_frame(frame).expect("shared frame was not allocated");
+    Ok(())
+}
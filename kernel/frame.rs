@@ -0,0 +1,346 @@
+//! Physical frame allocator
+//!
+//! Every 4KiB frame of RAM described by the devicetree gets a single bit in a bitmap.
+//! A set bit means the frame is either in use or reserved (firmware, the kernel image,
+//! the kernel heap, `/reserved-memory`, etc). The bitmap itself lives on the kernel heap.
+//!
+//! Frames shared between address spaces (copy-on-write after a fork) keep a count of their
+//! extra owners, freeing a shared frame only drops one of them.
+
+use core::fmt::Display;
+
+use owo_colors::{OwoColorize, colors::*};
+use ralloc::{collections::BTreeMap, vec, vec::Vec};
+use utils::sync::TicketLock;
+
+use crate::{__heap_end, dtree::DeviceTree, paging::PAGE_SIZE, traits::KSay};
+
+pub static FRAME_ALLOC: FrameAlloc = FrameAlloc::new();
+
+const BITS: usize = u64::BITS as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// No free frame (or run of frames) is left
+    OutOfMemory,
+    /// Address is not aligned to [`PAGE_SIZE`]
+    Unaligned,
+    /// Address is not managed by the allocator
+    OutOfRange,
+    /// Frame was freed without being allocated
+    NotAllocated,
+    /// Frame belongs to the kernel image, firmware or a reserved region, it's never handed out
+    Reserved,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub total: usize,
+    pub free: usize,
+    pub used: usize,
+    pub reserved: usize,
+}
+
+struct RawFrames {
+    /// Physical address of frame 0
+    base: usize,
+    /// Number of frames covered by `bitmap`
+    frames: usize,
+    bitmap: Vec<u64>,
+    /// Frames marked by [`RawFrames::reserve`], also set in `bitmap`
+    reserved_map: Vec<u64>,
+    /// Where the next single frame search begins (next-fit)
+    hint: usize,
+    free: usize,
+    reserved: usize,
+    /// Owners besides the first of every shared frame
+    shared: BTreeMap<usize, usize>,
+}
+
+pub struct FrameAlloc {
+    inner: TicketLock<Option<RawFrames>>,
+}
+
+impl KSay for FrameAlloc {
+    const NAME: &'static str = "frames";
+}
+
+impl RawFrames {
+    fn is_set(&self, frame: usize) -> bool {
+        self.bitmap[frame / BITS] & (1 << (frame % BITS)) != 0
+    }
+
+    fn set(&mut self, frame: usize) {
+        self.bitmap[frame / BITS] |= 1 << (frame % BITS);
+    }
+
+    fn clear(&mut self, frame: usize) {
+        self.bitmap[frame / BITS] &= !(1 << (frame % BITS));
+    }
+
+    fn is_reserved(&self, frame: usize) -> bool {
+        self.reserved_map[frame / BITS] & (1 << (frame % BITS)) != 0
+    }
+
+    /// Marks `[start, end)` as reserved, clamped to the managed range
+    fn reserve(&mut self, start: usize, end: usize) {
+        let start = start.max(self.base);
+        let end = end.min(self.base + self.frames * PAGE_SIZE);
+        if start >= end {
+            return;
+        }
+
+        let first = (start - self.base) / PAGE_SIZE;
+        let last = (end - self.base).div_ceil(PAGE_SIZE);
+        for frame in first..last {
+            if !self.is_set(frame) {
+                self.set(frame);
+                self.reserved_map[frame / BITS] |= 1 << (frame % BITS);
+                self.free -= 1;
+                self.reserved += 1;
+            }
+        }
+    }
+
+    fn index_of(&self, addr: usize) -> Result<usize, FrameError> {
+        if addr % PAGE_SIZE != 0 {
+            return Err(FrameError::Unaligned);
+        }
+        if addr < self.base || addr >= self.base + self.frames * PAGE_SIZE {
+            return Err(FrameError::OutOfRange);
+        }
+        Ok((addr - self.base) / PAGE_SIZE)
+    }
+
+    fn alloc_one(&mut self) -> Result<usize, FrameError> {
+        let words = self.bitmap.len();
+        let start = self.hint / BITS;
+
+        for i in 0..words {
+            let word = (start + i) % words;
+            if self.bitmap[word] == u64::MAX {
+                continue;
+            }
+
+            let frame = word * BITS + self.bitmap[word].trailing_ones() as usize;
+            // The last word may have bits past the end of memory
+            if frame >= self.frames {
+                continue;
+            }
+
+            self.set(frame);
+            self.free -= 1;
+            self.hint = frame + 1;
+            return Ok(frame);
+        }
+
+        Err(FrameError::OutOfMemory)
+    }
+
+    fn alloc_run(&mut self, count: usize) -> Result<usize, FrameError> {
+        let mut run = 0;
+        for frame in 0..self.frames {
+            if self.is_set(frame) {
+                run = 0;
+                continue;
+            }
+
+            run += 1;
+            if run == count {
+                let first = frame + 1 - count;
+                for frame in first..=frame {
+                    self.set(frame);
+                }
+                self.free -= count;
+                return Ok(first);
+            }
+        }
+
+        Err(FrameError::OutOfMemory)
+    }
+}
+
+impl FrameAlloc {
+    const fn new() -> Self {
+        FrameAlloc {
+            inner: TicketLock::new(None),
+        }
+    }
+
+    /// Builds the bitmap from the `/memory` node, then reserves the devicetree reserved
+    /// regions and everything up to the end of the kernel heap.
+    pub fn init(&self, dtree: &DeviceTree) {
+        let regions = dtree.memory_regions();
+        let (start, end) = regions
+            .iter()
+            .copied()
+            .reduce(|(s0, e0), (s1, e1)| (s0.min(s1), e0.max(e1)))
+            .expect("devicetree has no /memory node");
+
+        let base = start.next_multiple_of(PAGE_SIZE);
+        let frames = (end - base) / PAGE_SIZE;
+
+        let mut raw = RawFrames {
+            base,
+            frames,
+            bitmap: vec![0; frames.div_ceil(BITS)],
+            reserved_map: vec![0; frames.div_ceil(BITS)],
+            hint: 0,
+            free: frames,
+            reserved: 0,
+            shared: BTreeMap::new(),
+        };
+
+        // Holes between memory nodes
+        let mut sorted = regions.clone();
+        sorted.sort_unstable();
+        for pair in sorted.windows(2) {
+            raw.reserve(pair[0].1, pair[1].0);
+        }
+
+        for (start, end) in dtree.reserved_regions() {
+            raw.reserve(start, end);
+        }
+
+        // The devicetree is borrowed from for the lifetime of the kernel
+        let (start, end) = dtree.blob_range();
+        raw.reserve(start, end);
+
+        // Firmware, kernel image, boot stack and heap
+        raw.reserve(base, &raw mut __heap_end as usize);
+
+        self.inner.lock().replace(raw);
+    }
+
+    /// Allocates a single zeroed frame
+    pub fn alloc_frame(&self) -> Result<*mut u8, FrameError> {
+        self.alloc_frames(1)
+    }
+
+    /// Allocates `count` physically contiguous zeroed frames
+    pub fn alloc_frames(&self, count: usize) -> Result<*mut u8, FrameError> {
+        let addr = {
+            let mut lock = self.inner.lock();
+            let raw = lock.as_mut().expect("Frame allocator is uninitalized");
+
+            let frame = match count {
+                0 => return Err(FrameError::OutOfMemory),
+                1 => raw.alloc_one()?,
+                _ => raw.alloc_run(count)?,
+            };
+            (raw.base + frame * PAGE_SIZE) as *mut u8
+        };
+
+        unsafe { core::ptr::write_bytes(addr, 0, count * PAGE_SIZE) };
+        Ok(addr)
+    }
+
+    pub fn free_frame(&self, frame: *mut u8) -> Result<(), FrameError> {
+        self.free_frames(frame, 1)
+    }
+
+    /// Adds an owner to an allocated frame, it's only really freed once every owner freed it
+    pub fn share_frame(&self, frame: *mut u8) -> Result<(), FrameError> {
+        let mut lock = self.inner.lock();
+        let raw = lock.as_mut().expect("Frame allocator is uninitalized");
+
+        let index = raw.index_of(frame as usize)?;
+        if raw.is_reserved(index) {
+            return Err(FrameError::Reserved);
+        }
+        if !raw.is_set(index) {
+            return Err(FrameError::NotAllocated);
+        }
+
+        *raw.shared.entry(index).or_insert(0) += 1;
+        Ok(())
+    }
+
+    /// Number of owners of an allocated frame
+    pub fn owners(&self, frame: *mut u8) -> Result<usize, FrameError> {
+        let lock = self.inner.lock();
+        let raw = lock.as_ref().expect("Frame allocator is uninitalized");
+
+        let index = raw.index_of(frame as usize)?;
+        if !raw.is_set(index) {
+            return Err(FrameError::NotAllocated);
+        }
+
+        Ok(1 + raw.shared.get(&index).copied().unwrap_or(0))
+    }
+
+    pub fn free_frames(&self, frame: *mut u8, count: usize) -> Result<(), FrameError> {
+        let mut lock = self.inner.lock();
+        let raw = lock.as_mut().expect("Frame allocator is uninitalized");
+
+        let first = raw.index_of(frame as usize)?;
+        if first + count > raw.frames {
+            return Err(FrameError::OutOfRange);
+        }
+        if (first..first + count).any(|frame| raw.is_reserved(frame)) {
+            return Err(FrameError::Reserved);
+        }
+
+        if count == 1 && let Some(owners) = raw.shared.get_mut(&first) {
+            *owners -= 1;
+            if *owners == 0 {
+                raw.shared.remove(&first);
+            }
+            return Ok(());
+        }
+
+        if (first..first + count).any(|frame| !raw.is_set(frame)) {
+            return Err(FrameError::NotAllocated);
+        }
+
+        for frame in first..first + count {
+            raw.clear(frame);
+        }
+        raw.free += count;
+        raw.hint = raw.hint.min(first);
+
+        Ok(())
+    }
+
+    /// The `(start, end)` physical range covered by the allocator
+    pub fn range(&self) -> (usize, usize) {
+        let lock = self.inner.lock();
+        let raw = lock.as_ref().expect("Frame allocator is uninitalized");
+        (raw.base, raw.base + raw.frames * PAGE_SIZE)
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        let lock = self.inner.lock();
+        let raw = lock.as_ref().expect("Frame allocator is uninitalized");
+        FrameStats {
+            total: raw.frames,
+            free: raw.free,
+            used: raw.frames - raw.free - raw.reserved,
+            reserved: raw.reserved,
+        }
+    }
+
+    pub fn print_stats(&self) {
+        let (start, end) = self.range();
+        <Self as KSay>::kprint(format_args!(
+            "managing {:#x}..{:#x}: {}",
+            start,
+            end,
+            self.stats().fg::<BrightCyan>()
+        ));
+    }
+}
+
+impl Display for FrameStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{} frames, {} free ({} KiB), {} used, {} reserved",
+            self.total,
+            self.free,
+            self.free * PAGE_SIZE / 1024,
+            self.used,
+            self.reserved
+        )
+    }
+}
@@ -0,0 +1,295 @@
+//! Harts
+//!
+//! The boot hart brings the system up and then starts every other hart listed under `/cpus`
+//! with SBI HSM. Each hart gets its own idle process, whose kernel stack it boots on, and
+//! from then on picks processes off the shared run queue like the boot hart does.
+//!
+//! While in the kernel a hart keeps a pointer to its [`Hart`] in `tp`. Anything only ever
+//! touched by one hart lives there, no locking needed.
+
+use core::{
+    arch::{asm, naked_asm},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use ralloc::vec::Vec;
+
+use crate::{
+    asid::flush_all,
+    dtree::DeviceTree,
+    interrupt::{self, SSTATUS_SIE},
+    ipi::{self, IpiMessage},
+    paging::kernel_satp,
+    read_csr,
+    proc::{Process, create_idle, r#yield},
+    sbi::{self, Extension, HartState, sbi_hart_get_status, sbi_hart_start, sbi_hart_stop},
+    timer,
+    traits::KSay,
+    trap,
+};
+
+/// Hart ids at or above this are left alone
+pub const MAX_HARTS: usize = 8;
+
+static mut HARTS: [Hart; MAX_HARTS] = {
+    let mut harts = [const { Hart::new(0) }; MAX_HARTS];
+    let mut id = 0;
+    while id < MAX_HARTS {
+        harts[id].id = id;
+        id += 1;
+    }
+    harts
+};
+/// `satp` the secondaries switch to before touching their stacks
+static SECONDARY_SATP: AtomicUsize = AtomicUsize::new(0);
+/// Bit per hart running the scheduler, including the boot hart
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+/// Bit per hart with nothing to run, see [`kick_idle`]
+static IDLE: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Hart {
+    pub id: usize,
+    /// Process running on this hart
+    pub curr: Option<*mut Process>,
+    /// Runs whenever nothing else can, never on the run queue
+    pub idle: *mut Process,
+    /// Set once the running process used up its time slice, see [`crate::proc::preempt`]
+    pub need_resched: bool,
+    /// Process this hart just switched away from, see [`crate::proc::finish_switch`]
+    pub prev: Option<*mut Process>,
+    /// [`irq_save`]s not yet matched by an [`irq_restore`]
+    irq_depth: usize,
+    /// Whether interrupts were on before the outermost [`irq_save`]
+    irq_were_on: bool,
+}
+
+impl KSay for Hart {
+    const NAME: &'static str = "hart";
+}
+
+impl Hart {
+    const fn new(id: usize) -> Self {
+        Hart {
+            id,
+            curr: None,
+            idle: core::ptr::null_mut(),
+            need_resched: false,
+            prev: None,
+            irq_depth: 0,
+            irq_were_on: false,
+        }
+    }
+}
+
+/// Points `tp` at the block of hart `id`, the first thing every hart does
+///
+/// # Safety
+/// `id` has to be the hart this runs on
+pub unsafe fn set_tp(id: usize) {
+    unsafe { asm!("mv tp, {}", in(reg) &raw mut HARTS[id]) };
+}
+
+/// Only ever touched by the hart it belongs to
+pub fn this_hart() -> &'static mut Hart {
+    let hart: *mut Hart;
+    unsafe {
+        asm!("mv {}, tp", out(reg) hart, options(nomem, nostack));
+        &mut *hart
+    }
+}
+
+pub fn hart_id() -> usize {
+    this_hart().id
+}
+
+/// The process running on this hart
+pub fn curr_proc() -> Option<*mut Process> {
+    this_hart().curr
+}
+
+/// Turns interrupts off on this hart until the matching [`irq_restore`]. Nests, only the
+/// outermost pair actually changes anything.
+pub fn irq_save() {
+    let were_on = read_csr!("sstatus") & SSTATUS_SIE != 0;
+    unsafe { asm!("csrci sstatus, {sie}", sie = const SSTATUS_SIE) };
+
+    let hart = this_hart();
+    if hart.irq_depth == 0 {
+        hart.irq_were_on = were_on;
+    }
+    hart.irq_depth += 1;
+}
+
+pub fn irq_restore() {
+    let hart = this_hart();
+    hart.irq_depth = hart.irq_depth.checked_sub(1).expect("irq_restore without irq_save");
+    if hart.irq_depth == 0 && hart.irq_were_on {
+        unsafe { asm!("csrsi sstatus, {sie}", sie = const SSTATUS_SIE) };
+    }
+}
+
+/// Mask of the harts running the scheduler
+pub fn online() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Mask of every online hart but this one
+pub fn others() -> usize {
+    online() & !(1 << hart_id())
+}
+
+/// Records whether this hart is about to run its idle process. Has to happen under the
+/// scheduler lock, so a hart that found nothing to run is always seen by [`kick_idle`].
+pub fn mark_idle(idle: bool) {
+    let bit = 1 << hart_id();
+    if idle {
+        IDLE.fetch_or(bit, Ordering::SeqCst);
+    } else {
+        IDLE.fetch_and(!bit, Ordering::SeqCst);
+    }
+}
+
+/// Wakes a hart waiting in [`idle_loop`] after something was queued, otherwise it only
+/// notices at its next tick
+pub fn kick_idle() {
+    let idle = IDLE.load(Ordering::SeqCst) & !(1 << hart_id());
+    if idle != 0 {
+        ipi::send(1 << idle.trailing_zeros(), IpiMessage::Reschedule);
+    }
+}
+
+/// Takes this hart offline for good, for when a panic on another hart asks it to
+pub fn stop() -> ! {
+    unsafe { asm!("csrci sstatus, {sie}", sie = const SSTATUS_SIE) };
+    ONLINE.fetch_and(!(1 << hart_id()), Ordering::AcqRel);
+    let _ = sbi_hart_stop();
+
+    // Still here if the SBI can't stop harts
+    loop {
+        unsafe { asm!("wfi") };
+    }
+}
+
+/// Makes `idle` the idle process of `hart`, and what it's running until it first switches
+fn set_idle(hart: usize, idle: *mut Process) {
+    unsafe {
+        HARTS[hart].idle = idle;
+        HARTS[hart].curr = Some(idle);
+        (*idle).last_hart = hart;
+        (*idle).on_hart = true;
+        (*idle).running.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Gives the boot hart its idle process, which runs on the boot stack
+pub fn init_boot() {
+    let idle = create_idle().expect("failed to create the idle process");
+    set_idle(hart_id(), idle);
+    ONLINE.fetch_or(1 << hart_id(), Ordering::AcqRel);
+}
+
+/// Hart ids of every usable cpu in the devicetree
+fn hart_ids(dtree: &DeviceTree) -> Vec<usize> {
+    let Some(cpus) = dtree.search("/cpus") else {
+        return Vec::new();
+    };
+
+    let cells = cpus.addr_size_cells().unwrap_or((1, 0));
+    cpus.children()
+        .iter()
+        .filter(|node| node.base_name() == "cpu")
+        .filter(|node| node.find_prop("status").is_none_or(|status| status.value().starts_with(b"okay")))
+        .filter_map(|node| node.reg(cells).first().map(|&(id, _)| id))
+        .collect()
+}
+
+/// Starts every stopped hart in the devicetree. The scheduler, the process table and
+/// everything else they reach must be up already.
+pub fn start_secondaries(dtree: &DeviceTree) {
+    if !sbi::has(Extension::Hsm) {
+        Hart::kprint("no HSM extension, running on the boot hart only");
+        return;
+    }
+
+    SECONDARY_SATP.store(kernel_satp(), Ordering::Relaxed);
+    let boot = hart_id();
+
+    for id in hart_ids(dtree).into_iter().filter(|&id| id != boot) {
+        if id >= MAX_HARTS {
+            Hart::kprint(format_args!("ignoring hart {id}, only {MAX_HARTS} are supported"));
+            continue;
+        }
+
+        match sbi_hart_get_status(id as u64) {
+            Ok(state) if state == HartState::Stopped as i64 => {}
+            Ok(_) => {
+                Hart::kprint(format_args!("hart {id} isn't stopped, leaving it alone"));
+                continue;
+            }
+            Err(err) => {
+                Hart::kprint(format_args!("no status for hart {id}: {err:?}"));
+                continue;
+            }
+        }
+
+        let idle = match create_idle() {
+            Ok(idle) => idle,
+            Err(err) => {
+                Hart::kprint(format_args!("no idle process for hart {id}: {err:?}"));
+                continue;
+            }
+        };
+        set_idle(id, idle);
+
+        let stack = unsafe { (*idle).kstack_top() };
+        if let Err(err) = sbi_hart_start(id as u64, secondary_entry as *const () as usize, stack as u64) {
+            Hart::kprint(format_args!("failed to start hart {id}: {err:?}"));
+        }
+    }
+}
+
+/// Where secondaries start, with paging off, the hart id in a0 and the top of their idle
+/// process's kernel stack in a1. The kernel is identity mapped, so turning paging on
+/// first makes the stack reachable without moving the pc.
+#[unsafe(naked)]
+extern "C" fn secondary_entry() -> ! {
+    naked_asm!(
+        "la t0, {satp}",
+        "ld t0, 0(t0)",
+        "csrw satp, t0",
+        "sfence.vma",
+        "mv sp, a1",
+        "j {main}",
+        satp = sym SECONDARY_SATP,
+        main = sym secondary_main,
+    )
+}
+
+extern "C" fn secondary_main(id: usize) -> ! {
+    unsafe {
+        set_tp(id);
+        asm!("csrw stvec, {}", in(reg) trap::trap_entry as *const u8);
+        // We're in the kernel, see trap::trap_entry
+        asm!("csrw sscratch, zero");
+    }
+
+    interrupt::interrupt_enable();
+    timer::init_hart();
+
+    // Shootdowns only reach this hart from here on, drop whatever it cached before
+    let online = ONLINE.fetch_or(1 << hart_id(), Ordering::AcqRel) | (1 << hart_id());
+    flush_all();
+    Hart::kprint(format_args!("hart {} online, {} running", hart_id(), online.count_ones()));
+
+    idle_loop()
+}
+
+/// What every hart ends up running as its idle process
+pub fn idle_loop() -> ! {
+    loop {
+        r#yield();
+        // The idle loop is the only kernel code that takes interrupts, the next tick lands
+        // right after the wfi and hands the hart back to whoever is runnable
+        unsafe { asm!("csrsi sstatus, {sie}", "wfi", "csrci sstatus, {sie}", sie = const SSTATUS_SIE) }
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{println, sbi::sbi_set_timer, trap::SCAUSE_INT, write_csr};
+use crate::{ipi, plic, println, timer, trap::SCAUSE_INT, write_csr};
 
 #[macro_use]
 pub mod macros {
@@ -44,6 +44,8 @@ pub const SIE_TIMER_EXTERNAL_INTERRUPT_ENABLE: usize = 1 << 5;
 pub const SIE_SOFTWARE_EXTERNAL_INTERRUPT_ENABLE: usize = 1 << 1;
 /// Enables SIE interrupts as supervisor
 pub const SSTATUS_SIE: usize = 1 << 1;
+/// Pending supervisor software interrupt, what an IPI raises
+pub const SIP_SSIP: usize = 1 << 1;
 
 #[unsafe(no_mangle)]
 pub fn interrupt_enable() {
@@ -53,9 +55,6 @@ pub fn interrupt_enable() {
             | SIE_SUPERVISOR_EXTERNAL_INTERRUPT_ENABLE
             | SIE_SOFTWARE_EXTERNAL_INTERRUPT_ENABLE
     );
-
-    // this is about 2 seconds?
-    write_csr!("stimecmp", 0xffffff);
 }
 
 #[allow(unused)]
@@ -79,10 +78,9 @@ pub fn handle_interrupt(scause: usize, sepc: usize, stval: usize) {
     // );
 
     match scause {
-        0x8000000000000005 => {
-            let mtime = read_time!();
-            sbi_set_timer(mtime as u64 + 0xffffff);
-        }
+        0x8000000000000001 => ipi::handle_ipi(),
+        0x8000000000000005 => timer::handle_tick(),
+        0x8000000000000009 => plic::handle_external(),
         _ => (),
     }
 }
@@ -0,0 +1,65 @@
+//! Inter-processor interrupts
+//!
+//! Harts poke each other with SBI IPIs, which arrive as supervisor software interrupts. The
+//! reason rides along as a bit in the target's pending mask, several messages sent before
+//! the target gets to them are all handled by the one interrupt.
+//!
+//! Like every other interrupt they're only taken in user mode or while idling, so nothing
+//! may wait for a hart to handle one.
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    hart::{self, MAX_HARTS, hart_id},
+    interrupt::SIP_SSIP,
+    sbi::sbi_send_ipi,
+    traits::KSay,
+};
+
+/// Messages sent to each hart and not yet handled, a bit per [`IpiMessage`]
+static PENDING: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum IpiMessage {
+    /// Something was queued while the hart was idle, the interrupt alone gets it out of `wfi`
+    Reschedule = 0,
+    /// Another hart panicked
+    Stop = 1,
+}
+
+impl KSay for IpiMessage {
+    const NAME: &'static str = "ipi";
+}
+
+/// Sends `msg` to every hart in `hart_mask`
+pub fn send(hart_mask: usize, msg: IpiMessage) {
+    if hart_mask == 0 {
+        return;
+    }
+
+    for (id, pending) in PENDING.iter().enumerate() {
+        if hart_mask & (1 << id) != 0 {
+            pending.fetch_or(1 << msg as usize, Ordering::Release);
+        }
+    }
+
+    if let Err(err) = sbi_send_ipi(hart_mask as u64, 0) {
+        IpiMessage::kprint(format_args!("failed to send {msg:?} to harts {hart_mask:#b}: {err:?}"));
+    }
+}
+
+/// Handles every message pending for this hart
+pub fn handle_ipi() {
+    // Cleared first, anything sent from here on raises it again
+    unsafe { asm!("csrc sip, {}", in(reg) SIP_SSIP) };
+    let pending = PENDING[hart_id()].swap(0, Ordering::Acquire);
+
+    if pending & (1 << IpiMessage::Stop as usize) != 0 {
+        hart::stop();
+    }
+    // Reschedule needs nothing else, the idle loop yields right after its wfi
+}
@@ -0,0 +1,118 @@
+//! Kernel stacks
+//!
+//! Every process gets a kernel stack mapped into a region of the kernel address space shared
+//! by all page tables, with an unmapped guard page below each one. Overflowing a kernel stack
+//! faults instead of quietly corrupting whatever memory is next to it.
+//!
+//! Slots are reused, and any hart may switch to any process, so mapping or unmapping a stack
+//! is shot down on every hart (see [`crate::tlb`]).
+
+use ralloc::vec::Vec;
+use utils::sync::TicketLock;
+
+use crate::{
+    frame::FRAME_ALLOC,
+    paging::{
+        GIGAPAGE_SIZE, PAGE_R, PAGE_SIZE, PAGE_W, PagingError, map_kernel_page,
+        reserve_kernel_region, unmap_kernel_page,
+    },
+    tlb,
+};
+
+/// Start of the region, the first root entry of the upper half
+pub const KSTACK_BASE: usize = 0xffff_ffc0_0000_0000;
+pub const KSTACK_SIZE: usize = 4 * PAGE_SIZE;
+/// Guard page and stack
+const SLOT_SIZE: usize = PAGE_SIZE + KSTACK_SIZE;
+const SLOTS: usize = GIGAPAGE_SIZE / SLOT_SIZE;
+
+static FREE_SLOTS: TicketLock<SlotAlloc> = TicketLock::new(SlotAlloc { free: Vec::new(), next: 0 });
+
+struct SlotAlloc {
+    /// Slots that were handed out and freed again
+    free: Vec<usize>,
+    /// Lowest slot never handed out
+    next: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KstackError {
+    /// Every slot of the region is in use
+    NoSlots,
+    Paging(PagingError),
+}
+
+impl From<PagingError> for KstackError {
+    fn from(err: PagingError) -> Self {
+        KstackError::Paging(err)
+    }
+}
+
+#[derive(Debug)]
+pub struct KernelStack {
+    slot: usize,
+}
+
+/// Sets up the region's table. Must happen before any process is created.
+pub fn init() -> Result<(), PagingError> {
+    reserve_kernel_region(KSTACK_BASE)
+}
+
+impl SlotAlloc {
+    fn alloc(&mut self) -> Option<usize> {
+        self.free.pop().or_else(|| {
+            let slot = self.next;
+            (slot < SLOTS).then(|| {
+                self.next += 1;
+                slot
+            })
+        })
+    }
+}
+
+impl KernelStack {
+    pub fn alloc() -> Result<KernelStack, KstackError> {
+        let slot = FREE_SLOTS.lock().alloc().ok_or(KstackError::NoSlots)?;
+        let stack = KernelStack { slot };
+
+        for page in (stack.bottom()..stack.top()).step_by(PAGE_SIZE) {
+            let mapped = FRAME_ALLOC
+                .alloc_frame()
+                .map_err(|_| PagingError::OutOfMemory)
+                .and_then(|frame| map_kernel_page(page, frame as usize, PAGE_R | PAGE_W));
+
+            if let Err(err) = mapped {
+                unsafe { stack.free() };
+                return Err(err.into());
+            }
+        }
+        tlb::shootdown_range(stack.bottom(), KSTACK_SIZE);
+
+        Ok(stack)
+    }
+
+    pub fn bottom(&self) -> usize {
+        KSTACK_BASE + self.slot * SLOT_SIZE + PAGE_SIZE
+    }
+
+    pub fn top(&self) -> usize {
+        self.bottom() + KSTACK_SIZE
+    }
+
+    /// Unmaps and frees whatever pages of the stack are mapped
+    ///
+    /// # Safety
+    /// Nothing may be running on the stack
+    pub unsafe fn free(self) {
+        let frames: [_; KSTACK_SIZE / PAGE_SIZE] =
+            core::array::from_fn(|i| unmap_kernel_page(self.bottom() + i * PAGE_SIZE).ok());
+        // No hart may still reach the frames once they're handed out again
+        tlb::shootdown_range(self.bottom(), KSTACK_SIZE);
+
+        for frame in frames.into_iter().flatten() {
+            FRAME_ALLOC.free_frame(frame.0 as *mut u8).expect("kernel stack frame was not allocated");
+        }
+
+        FREE_SLOTS.lock().free.push(self.slot);
+    }
+}
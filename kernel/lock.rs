@@ -0,0 +1,75 @@
+//! Interrupt safe spinlocks
+//!
+//! A plain spinlock deadlocks the moment an interrupt handler wants a lock the code it
+//! interrupted is holding. [`IrqSpinLock`] turns interrupts off on the hart for as long as it's
+//! held, so nothing on the same hart can get in between. Holding several nests, interrupts
+//! only come back once the last one is released (see [`crate::hart::irq_save`]).
+
+use core::{
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+
+use utils::sync::{TicketLock, TicketLockGuard};
+
+use crate::hart::{irq_restore, irq_save};
+
+#[derive(Debug)]
+pub struct IrqSpinLock<T: ?Sized> {
+    inner: TicketLock<T>,
+}
+
+pub struct IrqSpinLockGuard<'a, T: ?Sized> {
+    inner: ManuallyDrop<TicketLockGuard<'a, T>>,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        IrqSpinLock { inner: TicketLock::new(value) }
+    }
+}
+
+impl<T: ?Sized> IrqSpinLock<T> {
+    /// Never hold it across a context switch
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        irq_save();
+        IrqSpinLockGuard {
+            inner: ManuallyDrop::new(self.inner.lock()),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<'_, T>> {
+        irq_save();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSpinLockGuard {
+                inner: ManuallyDrop::new(guard),
+            }),
+            None => {
+                irq_restore();
+                None
+            }
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for IrqSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // Unlocked before interrupts can come back
+        unsafe { ManuallyDrop::drop(&mut self.inner) };
+        irq_restore();
+    }
+}
@@ -23,11 +23,22 @@
 extern crate alloc as ralloc;
 
 mod alloc;
+mod asid;
+mod console;
+mod frame;
+mod hart;
+mod kstack;
+mod lock;
 #[macro_use]
 mod interrupt;
+mod ipi;
 mod paging;
+mod plic;
+mod power;
 mod proc;
 mod sbi;
+mod sched;
+mod slab;
 #[macro_use]
 mod print;
 #[macro_use]
@@ -36,23 +47,33 @@ mod uart;
 mod virtio;
 mod ext2;
 mod syscall;
+mod timer;
+mod tlb;
 mod elf;
+mod fault;
 pub mod traits;
 mod registers;
 
 mod dtree;
 mod user;
+mod vma;
+mod wait;
 
 use core::arch::asm;
+use core::fmt::Write;
 use core::panic::PanicInfo;
 
-use spin::lazy::Lazy;
-
 use crate::alloc::GLOBAL_ALLOC;
+use crate::asid::ASIDS;
 use crate::dtree::{DeviceTreeHeader, DeviceTreeNode};
-use crate::proc::{create_process, r#yield, Process};
+use crate::frame::FRAME_ALLOC;
+use crate::paging::{PAGE_SIZE, PAGE_TABLE_CACHE};
+use crate::power::PanicPolicy;
+use crate::proc::{create_process, spawn_kernel_threads, PROC_CACHE};
+use crate::sbi::ResetReason;
 use crate::uart::{UART16550, UartInitError, uart16550::init_uart_16650 as init_uart};
-use crate::user::{_binary__shell_bin_end, _binary__shell_bin_start};
+use crate::user::{_binary__shell_elf_end, _binary__shell_elf_start};
+use crate::virtio::VIRTIO_BLK_PADDR;
 
 unsafe extern "C" {
     static mut __bss: u8;
@@ -75,11 +96,6 @@ pub extern "C" fn boot() -> ! {
     }
 }
 
-static mut PROC_IDLE: Lazy<*mut Process> =
-    Lazy::new(|| create_process(core::ptr::null_mut(), 0).unwrap());
-
-pub static mut PROC_CURR: Option<*mut Process> = None;
-
 // Use trait for VFS later lol
 pub trait Filesystem {}
 
@@ -97,20 +113,29 @@ fn main() -> ! {
     };
 
     unsafe {
+        hart::set_tp(hart_start);
+
         let bss_start = &raw mut __bss;
         let bss_size = (&raw mut __bss_end as usize) - (&raw mut __bss as usize);
         core::ptr::write_bytes(bss_start, 0, bss_size);
 
         asm!("csrw stvec, {}", in(reg) trap::trap_entry as *const u8);
+        // We're in the kernel, see trap::trap_entry
+        asm!("csrw sscratch, zero");
     }
 
     println!("Booting JimOS");
     println!("Starting Hart: {hart_start}");
+    sbi::init();
 
     GLOBAL_ALLOC.init(&raw mut __heap, &raw mut __heap_end);
 
     let dtree = dtree::parse(devicetree);
     dtree.print_properties();
+
+    FRAME_ALLOC.init(&dtree);
+    FRAME_ALLOC.print_stats();
+
     // TODO: use the [`crate::traits::Init`] trait to utilize
     // the devicetree to initalize drivers, if applicable.
     // This may not be the most efficient way to do this.
@@ -120,39 +145,81 @@ fn main() -> ! {
         |src| usize::from_str_radix(src, 16).ok().map(|num| num as *mut u8)
     );
 
+    let (_, frames_end) = FRAME_ALLOC.range();
+    paging::init_kernel_table(&raw mut __kernel_base as usize, frames_end)
+        .expect("failed to build kernel page table");
+    let addr = addr.map(|addr| {
+        paging::map_kernel_mmio(addr as usize, PAGE_SIZE).expect("failed to map uart") as *mut u8
+    });
+    let virtio = paging::map_kernel_mmio(VIRTIO_BLK_PADDR as usize, PAGE_SIZE).expect("failed to map virtio");
+    kstack::init().expect("failed to reserve kernel stack region");
+    unsafe {
+        switch_page_table!(paging::kernel_satp());
+    }
+    ASIDS.lock().init(asid::detect_asid_bits());
+    plic::init(&dtree, hart_start);
+
     let _ = addr
         .ok_or_else(|| UartInitError)
         .and_then(|addr| init_uart(addr));
 
     UART16550.get().unwrap().lock().set_printer();
 
-    virtio::init_virtio();
+    virtio::init_virtio(virtio);
+    virtio::init_irq(&dtree);
+    console::init(&dtree);
 
     interrupt::interrupt_enable();
+    timer::init(&dtree);
+    sched::init(&dtree);
+    power::init(&dtree);
 
     ext2::init();
 
-    unsafe {
-        PROC_CURR = Some(*PROC_IDLE);
-    }
+    hart::init_boot();
+    spawn_kernel_threads();
+    virtio::spawn_contention_check(&dtree);
 
     let _ = create_process(
-        &raw mut _binary__shell_bin_start,
-        &raw mut _binary__shell_bin_end as usize - &raw mut _binary__shell_bin_start as usize,
+        &raw mut _binary__shell_elf_start,
+        &raw mut _binary__shell_elf_end as usize - &raw mut _binary__shell_elf_start as usize,
+        &[b"shell"],
     );
 
-    r#yield();
+    hart::start_secondaries(&dtree);
+
+    PROC_CACHE.print_stats();
+    PAGE_TABLE_CACHE.print_stats();
 
     // Entering kernel busy loop
     println!("Entering kernel wait period");
-    loop {
-        unsafe { asm!("wfi") }
-    }
+    hart::idle_loop()
 }
 
 #[panic_handler]
 pub fn panic_handler(info: &PanicInfo) -> ! {
-    println!("PanicInfo: {info}");
+    // Nothing else should keep running on top of whatever broke
+    ipi::send(hart::others(), ipi::IpiMessage::Stop);
+
+    // The panic may have happened mid print
+    match print::PRINTER.try_lock() {
+        Some(mut printer) => {
+            let _ = writeln!(printer, "PanicInfo: {info}");
+        }
+        None => {
+            let _ = writeln!(print::SbiPrinter, "PanicInfo: {info}");
+        }
+    }
+
+    let reset = match power::panic_policy() {
+        PanicPolicy::Halt => None,
+        PanicPolicy::Reboot => Some(power::reboot(ResetReason::SystemFailure)),
+        PanicPolicy::Shutdown => Some(power::shutdown(ResetReason::SystemFailure)),
+    };
+    if let Some(err) = reset {
+        let _ = writeln!(print::SbiPrinter, "reset failed ({err:?}), halting");
+    }
+
     loop {
         unsafe {
             core::arch::asm!("wfi");
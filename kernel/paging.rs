@@ -23,10 +23,10 @@
 
 use core::{alloc::{GlobalAlloc, Layout}, ops::{Deref, DerefMut}, slice};
 
-use crate::alloc::GLOBAL_ALLOC;
+use crate::{frame::{FRAME_ALLOC, FrameError}, lock::IrqSpinLock, slab::Cache};
 
 const SATP_PPN: usize = 0;
-const SATP_ASID: usize = 44;
+pub const SATP_ASID: usize = 44;
 const SATP_MODE: usize = 60;
 
 const VADDR_VPN2: usize = 0b111111111;
@@ -52,13 +52,15 @@ pub const PAGE_X: usize = 1 << 3;
 /// User (accessible in user mode)
 pub const PAGE_U: usize = 1 << 4;
 /// Global (exist in every address space)
-const PAGE_G: usize = 1 << 5;
+pub const PAGE_G: usize = 1 << 5;
 /// Accessed (page has been accessed since last time A was set to 0)
 const PAGE_A: usize = 1 << 6;
 /// Dirty (page has been changed since last time D was set to 0)
 const PAGE_D: usize = 1 << 7;
 /// RSW
 const PAGE_RSW: usize = 0b11 << 8;
+/// Copy-on-write, first of the RSW bits. Set on pages that were writable before being shared
+pub const PAGE_COW: usize = PAGE_RSW & (1 << 8);
 ///PPN0
 const PAGE_PPN0: usize = 0b111111111 << 10;
 ///PPN1
@@ -72,8 +74,77 @@ const PAGE_N: usize = 1 << 63;
 
 /// Alignment and Size for a 4KiB page
 pub const PAGE_SIZE: usize = 4096;
+/// Alignment and Size for a 2MiB megapage (leaf in a level 1 table)
+pub const MEGAPAGE_SIZE: usize = 1 << VPN1_SHIFT;
+/// Alignment and Size for a 1GiB gigapage (leaf in the root table)
+pub const GIGAPAGE_SIZE: usize = 1 << VPN2_SHIFT;
 pub const PAGE_TABLE_SIZE: usize = const { 2usize.pow(9) };
 
+/// Flags a leaf entry may carry, everything else is the PPN or reserved
+const PAGE_FLAGS: usize = 0b11_1111_1111;
+const PAGE_RWX: usize = PAGE_R | PAGE_W | PAGE_X;
+
+/// Size of a leaf mapping, the discriminant is the level of the table the leaf lives in
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    Kilo = 0,
+    Mega = 1,
+    Giga = 2,
+}
+
+impl PageSize {
+    pub const fn bytes(self) -> usize {
+        match self {
+            PageSize::Kilo => PAGE_SIZE,
+            PageSize::Mega => MEGAPAGE_SIZE,
+            PageSize::Giga => GIGAPAGE_SIZE,
+        }
+    }
+
+    const fn from_level(level: usize) -> PageSize {
+        match level {
+            0 => PageSize::Kilo,
+            1 => PageSize::Mega,
+            _ => PageSize::Giga,
+        }
+    }
+
+    /// Largest size that `vaddr`, `paddr` and `len` are all aligned to and that fits in `len`
+    pub fn largest_fit(vaddr: usize, paddr: usize, len: usize) -> PageSize {
+        [PageSize::Giga, PageSize::Mega]
+            .into_iter()
+            .find(|size| {
+                let bytes = size.bytes();
+                vaddr % bytes == 0 && paddr % bytes == 0 && len >= bytes
+            })
+            .unwrap_or(PageSize::Kilo)
+    }
+}
+
+/// Bits of the virtual address that index the table at `level`
+const fn vpn(vaddr: usize, level: usize) -> usize {
+    (vaddr >> (VPN0_SHIFT + 9 * level)) & VADDR_VPN0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingError {
+    UnalignedVAddr,
+    UnalignedPAddr,
+    /// The virtual address already has a mapping
+    AlreadyMapped,
+    /// The virtual address has no mapping
+    NotMapped,
+    OutOfMemory,
+    Frame(FrameError),
+}
+
+impl From<FrameError> for PagingError {
+    fn from(err: FrameError) -> Self {
+        PagingError::Frame(err)
+    }
+}
+
 #[derive(Debug)]
 #[repr(transparent)]
 pub struct Entry(usize);
@@ -82,6 +153,27 @@ impl Entry {
     pub fn new(addr: usize, flags: usize) -> Entry {
         Entry(((addr >> PPN_SHIFT) << PPN0_PTE_SHIFT) | flags)
     }
+
+    pub fn is_valid(&self) -> bool {
+        self.0 & PAGE_V != 0
+    }
+
+    /// A valid entry with any of R/W/X set maps memory, otherwise it points to the next table
+    pub fn is_leaf(&self) -> bool {
+        self.is_valid() && self.0 & PAGE_RWX != 0
+    }
+
+    pub fn addr(&self) -> usize {
+        ((self.0 & (PAGE_PPN0 | PAGE_PPN1 | PAGE_PPN2)) >> PPN0_PTE_SHIFT) << PPN_SHIFT
+    }
+
+    pub fn flags(&self) -> usize {
+        self.0 & PAGE_FLAGS
+    }
+
+    fn table(&self) -> *mut PageTable {
+        self.addr() as *mut PageTable
+    }
 }
 
 #[repr(transparent)]
@@ -89,6 +181,38 @@ pub struct VAddr(pub *const ());
 #[repr(transparent)]
 pub struct PAddr(pub *const ());
 
+pub static PAGE_TABLE_CACHE: Cache<PageTable> = Cache::new("page_table");
+
+/// Root table holding the kernel mappings, built once at boot by [`init_kernel_table`]. Any
+/// hart may change the shared part of it (kernel stacks, MMIO), always with the lock held.
+static KERNEL_PAGE_TABLE: IrqSpinLock<KernelTable> = IrqSpinLock::new(KernelTable(core::ptr::null_mut()));
+
+struct KernelTable(*mut PageTable);
+
+// Only reached through the lock
+unsafe impl Send for KernelTable {}
+
+impl KernelTable {
+    fn get(&self) -> *mut PageTable {
+        assert!(!self.0.is_null(), "kernel page table is uninitalized");
+        self.0
+    }
+}
+
+/// The kernel's root table, for `satp` and telling kernel threads apart. Change it only
+/// through the functions in this module.
+pub fn kernel_page_table() -> *mut PageTable {
+    KERNEL_PAGE_TABLE.lock().get()
+}
+
+/// Root entry covering user space. Every process gets its own table below it, every other
+/// root entry points straight at the kernel's tables.
+pub const USER_ROOT_INDEX: usize = 0;
+
+/// Devices are mapped at their physical address plus this, in the root entry right after the
+/// kernel stacks. Keeps their registers out of [`USER_ROOT_INDEX`].
+pub const MMIO_BASE: usize = 0xffff_ffc0_4000_0000;
+
 #[derive(Debug)]
 #[repr(C, align(4096))]
 pub struct PageTable([Entry; PAGE_TABLE_SIZE]);
@@ -107,57 +231,366 @@ impl DerefMut for PageTable {
     }
 }
 
+/// Flushes the local TLB entries for `vaddr`
+pub fn flush_page(vaddr: usize) {
+    unsafe { core::arch::asm!("sfence.vma {}, zero", in(reg) vaddr) }
+}
+
 impl PageTable {
     pub fn alloc() -> *mut PageTable {
-        (unsafe { GLOBAL_ALLOC.alloc_zeroed(Layout::new::<PageTable>()) }) as *mut PageTable
+        PAGE_TABLE_CACHE.alloc_zeroed().expect("out of memory for page table")
     }
 
-    pub fn map_page(&mut self, vaddr: VAddr, paddr: PAddr, flags: usize) {
-        let table2 = &mut self.0;
-        if !vaddr.0.is_aligned_to(PAGE_SIZE) {
-            // TODO: Change panic
-            panic!("unaligned vaddr");
+    /// Walks to the entry for `vaddr` in the table at `level`, creating missing tables if
+    /// `create` is set. Running into a leaf above `level` is an error.
+    fn walk(&mut self, vaddr: usize, level: usize, create: bool) -> Result<&mut Entry, PagingError> {
+        let mut table: *mut PageTable = self;
+
+        for depth in (level + 1..=2).rev() {
+            let entry = unsafe { &mut (*table).0[vpn(vaddr, depth)] };
+
+            if !entry.is_valid() {
+                if !create {
+                    return Err(PagingError::NotMapped);
+                }
+                // Create next table if it doesn't exist
+                let page = PAGE_TABLE_CACHE.alloc_zeroed().map_err(|_| PagingError::OutOfMemory)?;
+                *entry = Entry::new(page as usize, PAGE_V);
+            } else if entry.is_leaf() {
+                return Err(PagingError::AlreadyMapped);
+            }
+
+            table = entry.table();
         }
 
-        if !paddr.0.is_aligned_to(PAGE_SIZE) {
-            // TODO: Change panic
-            panic!("unaligned paddr");
+        Ok(unsafe { &mut (*table).0[vpn(vaddr, level)] })
+    }
+
+    /// Finds the leaf entry mapping `vaddr`, whatever its size
+    fn find_leaf(&mut self, vaddr: usize) -> Result<(&mut Entry, PageSize), PagingError> {
+        let mut table: *mut PageTable = self;
+
+        for level in (0..=2).rev() {
+            let entry = unsafe { &mut (*table).0[vpn(vaddr, level)] };
+
+            if !entry.is_valid() {
+                return Err(PagingError::NotMapped);
+            }
+
+            if entry.is_leaf() {
+                return Ok((entry, PageSize::from_level(level)));
+            }
+
+            table = entry.table();
+        }
+
+        Err(PagingError::NotMapped)
+    }
+
+    pub fn map_page(&mut self, vaddr: VAddr, paddr: PAddr, flags: usize) -> Result<(), PagingError> {
+        self.map_huge(vaddr, paddr, PageSize::Kilo, flags)
+    }
+
+    /// Maps a single leaf of `size`
+    pub fn map_huge(&mut self, vaddr: VAddr, paddr: PAddr, size: PageSize, flags: usize) -> Result<(), PagingError> {
+        if !vaddr.0.is_aligned_to(size.bytes()) {
+            return Err(PagingError::UnalignedVAddr);
+        }
+
+        if !paddr.0.is_aligned_to(size.bytes()) {
+            return Err(PagingError::UnalignedPAddr);
         }
 
         let vaddr: usize = vaddr.0 as usize;
         let paddr: usize = paddr.0 as usize;
 
-        let vpn2 = (vaddr >> VPN2_SHIFT) & VADDR_VPN2;
-        let table1 = if (table2[vpn2].0 & PAGE_V) == 0 {
-            // Create next table if it doesn't exist
-            let page = PageTable::alloc();
-            table2[vpn2] = Entry::new(page as usize, PAGE_V);
-            unsafe {
-                slice::from_raw_parts_mut(page as *mut Entry, PAGE_TABLE_SIZE)
+        let entry = self.walk(vaddr, size as usize, true)?;
+        if entry.is_valid() {
+            // Either a leaf, or a table we'd be leaking
+            return Err(PagingError::AlreadyMapped);
+        }
+
+        *entry = Entry::new(paddr, flags | PAGE_V);
+        Ok(())
+    }
+
+    /// Maps `len` bytes, using the largest page size the alignment of both addresses allows
+    /// at each step
+    pub fn map_range(&mut self, vaddr: VAddr, paddr: PAddr, len: usize, flags: usize) -> Result<(), PagingError> {
+        if !vaddr.0.is_aligned_to(PAGE_SIZE) {
+            return Err(PagingError::UnalignedVAddr);
+        }
+
+        if !paddr.0.is_aligned_to(PAGE_SIZE) {
+            return Err(PagingError::UnalignedPAddr);
+        }
+
+        let (vaddr, paddr) = (vaddr.0 as usize, paddr.0 as usize);
+        let len = len.next_multiple_of(PAGE_SIZE);
+
+        let mut offset = 0;
+        while offset < len {
+            let size = PageSize::largest_fit(vaddr + offset, paddr + offset, len - offset);
+            self.map_huge(
+                VAddr((vaddr + offset) as *const ()),
+                PAddr((paddr + offset) as *const ()),
+                size,
+                flags,
+            )?;
+            offset += size.bytes();
+        }
+
+        Ok(())
+    }
+
+    /// Removes the leaf mapping `vaddr`, returning the physical page it pointed to.
+    /// `vaddr` must be aligned to the size of that leaf.
+    /// Tables left empty are kept around until [`PageTable::free`].
+    pub fn unmap_page(&mut self, vaddr: VAddr) -> Result<PAddr, PagingError> {
+        let vaddr = vaddr.0 as usize;
+        let (entry, size) = self.find_leaf(vaddr)?;
+        if vaddr % size.bytes() != 0 {
+            return Err(PagingError::UnalignedVAddr);
+        }
+
+        let paddr = entry.addr();
+        *entry = Entry(0);
+        flush_page(vaddr);
+
+        Ok(PAddr(paddr as *const ()))
+    }
+
+    /// Replaces the flags of the leaf mapping `vaddr`, `PAGE_V` is always kept
+    pub fn protect(&mut self, vaddr: VAddr, flags: usize) -> Result<(), PagingError> {
+        let vaddr = vaddr.0 as usize;
+        let (entry, size) = self.find_leaf(vaddr)?;
+        if vaddr % size.bytes() != 0 {
+            return Err(PagingError::UnalignedVAddr);
+        }
+
+        *entry = Entry::new(entry.addr(), (flags & PAGE_FLAGS) | PAGE_V);
+        flush_page(vaddr);
+
+        Ok(())
+    }
+
+    /// Returns the physical address `vaddr` maps to (including the page offset) and the
+    /// flags of the mapping
+    pub fn translate(&self, vaddr: VAddr) -> Option<(PAddr, usize)> {
+        let vaddr = vaddr.0 as usize;
+        let mut table: *const PageTable = self;
+
+        for level in (0..=2).rev() {
+            let entry = unsafe { &(*table).0[vpn(vaddr, level)] };
+
+            if !entry.is_valid() {
+                return None;
             }
-        } else {
-            let page = ((table2[vpn2].0 >> PPN0_PTE_SHIFT) << PPN_SHIFT) as *const Entry;
-            unsafe {
-                slice::from_raw_parts_mut(page as *mut Entry, PAGE_TABLE_SIZE)
+
+            if entry.is_leaf() {
+                let offset = vaddr & (PageSize::from_level(level).bytes() - 1);
+                return Some((PAddr((entry.addr() + offset) as *const ()), entry.flags()));
             }
-        };
 
-        let vpn1 = (vaddr >> VPN1_SHIFT) & VADDR_VPN1;
-        let table0 = if (table1[vpn1].0 & PAGE_V) == 0 {
-            // Create next table
-            let page = PageTable::alloc();
-            table1[vpn1] = Entry::new(page as usize, PAGE_V);
-            unsafe {
-                slice::from_raw_parts_mut(page as *mut Entry, PAGE_TABLE_SIZE)
+            table = entry.table();
+        }
+
+        None
+    }
+
+    /// Calls `f` with the virtual address and entry of every leaf not shared with the kernel
+    fn for_each_user_leaf(
+        &mut self,
+        mut f: impl FnMut(usize, &mut Entry) -> Result<(), PagingError>,
+    ) -> Result<(), PagingError> {
+        fn visit(
+            table: *mut PageTable,
+            level: usize,
+            base: usize,
+            f: &mut impl FnMut(usize, &mut Entry) -> Result<(), PagingError>,
+        ) -> Result<(), PagingError> {
+            for (index, entry) in unsafe { (*table).0.iter_mut().enumerate() } {
+                if !entry.is_valid() || entry.flags() & PAGE_G != 0 {
+                    continue;
+                }
+
+                let vaddr = base | (index << (VPN0_SHIFT + 9 * level));
+                if entry.is_leaf() {
+                    f(vaddr, entry)?;
+                } else if level > 0 {
+                    visit(entry.table(), level - 1, vaddr, f)?;
+                }
+            }
+            Ok(())
+        }
+
+        visit(self, 2, 0, &mut f)
+    }
+
+    /// Creates a table for a child process sharing every user page with this one. Writable
+    /// pages become read-only [`PAGE_COW`] pages in both, the store page fault handler
+    /// copies them when either side writes.
+    pub fn fork_user(&mut self) -> Result<*mut PageTable, PagingError> {
+        let child = PageTable::new_user()?;
+
+        let result = self.for_each_user_leaf(|vaddr, entry| {
+            let mut flags = entry.flags();
+            if flags & PAGE_W != 0 {
+                flags = (flags & !PAGE_W) | PAGE_COW;
+                *entry = Entry::new(entry.addr(), flags);
+                flush_page(vaddr);
             }
-        } else {
-            let page = ((table1[vpn1].0 >> PPN0_PTE_SHIFT) << PPN_SHIFT) as *const Entry;
+
+            let frame = entry.addr() as *mut u8;
+            FRAME_ALLOC.share_frame(frame)?;
+
+            let mapped = unsafe {
+                (*child).map_page(VAddr(vaddr as *const ()), PAddr(frame as *const ()), flags)
+            };
+            if mapped.is_err() {
+                // The child's cleanup never sees the frame, drop its share here
+                FRAME_ALLOC.free_frame(frame)?;
+            }
+            mapped
+        });
+
+        if let Err(err) = result {
             unsafe {
-                slice::from_raw_parts_mut(page as *mut Entry, PAGE_TABLE_SIZE)
+                (*child).unmap_user();
+                PageTable::free(child);
             }
-        };
+            return Err(err);
+        }
 
-        let vpn0 = (vaddr >> VPN0_SHIFT) & VADDR_VPN0;
-        table0[vpn0] = Entry::new(paddr as usize, flags | PAGE_V);
+        Ok(child)
     }
+
+    /// Unmaps every user page, giving its frame back (or dropping this table's share of it)
+    pub fn unmap_user(&mut self) {
+        let _ = self.for_each_user_leaf(|vaddr, entry| {
+            let frame = entry.addr() as *mut u8;
+            *entry = Entry(0);
+            flush_page(vaddr);
+            FRAME_ALLOC.free_frame(frame).expect("user page is not an allocated frame");
+            Ok(())
+        });
+    }
+
+    /// Creates a root table for a process, sharing every kernel mapping.
+    /// Shared entries are marked [`PAGE_G`] so [`PageTable::free`] leaves them alone.
+    pub fn new_user() -> Result<*mut PageTable, PagingError> {
+        let root = PAGE_TABLE_CACHE.alloc_zeroed().map_err(|_| PagingError::OutOfMemory)?;
+
+        let kernel = KERNEL_PAGE_TABLE.lock();
+        let kernel = kernel.get();
+        unsafe {
+            assert!(!(*kernel).0[USER_ROOT_INDEX].is_valid(), "kernel mapping in user space");
+
+            for (index, entry) in (*kernel).iter().enumerate() {
+                if entry.is_valid() && index != USER_ROOT_INDEX {
+                    (*root).0[index] = Entry(entry.0 | PAGE_G);
+                }
+            }
+        }
+
+        Ok(root)
+    }
+
+    /// Releases `table` and every table below it. Mapped pages are not freed, that's up to
+    /// whoever owns them, and neither are tables shared with the kernel.
+    ///
+    /// # Safety
+    /// `table` must have come from [`PageTable::alloc`] and not be in use by any hart
+    pub unsafe fn free(table: *mut PageTable) {
+        unsafe {
+            for entry in (*table).iter() {
+                if entry.is_valid() && !entry.is_leaf() && entry.flags() & PAGE_G == 0 {
+                    PageTable::free(entry.table());
+                }
+            }
+
+            PAGE_TABLE_CACHE.free(table).expect("page table was not allocated from the cache");
+        }
+    }
+}
+
+/// Builds [`KERNEL_PAGE_TABLE`], identity mapping `[start, end)` (the kernel image, heap and
+/// every frame) as global. Processes created afterwards share it through [`PageTable::new_user`].
+pub fn init_kernel_table(start: usize, end: usize) -> Result<(), PagingError> {
+    assert!(
+        start >= GIGAPAGE_SIZE * (USER_ROOT_INDEX + 1),
+        "kernel can't live in the user root entry"
+    );
+
+    let table = PageTable::alloc();
+    unsafe {
+        (*table).map_range(
+            VAddr(start as *const ()),
+            PAddr(start as *const ()),
+            end - start,
+            PAGE_R | PAGE_W | PAGE_X | PAGE_G,
+        )?;
+
+    }
+    KERNEL_PAGE_TABLE.lock().0 = table;
+
+    Ok(())
+}
+
+/// Maps a device's registers at [`MMIO_BASE`] `+ addr`, returning where `addr` ended up.
+/// Shows up in every address space, even ones created before.
+pub fn map_kernel_mmio(addr: usize, len: usize) -> Result<usize, PagingError> {
+    let start = addr & !(PAGE_SIZE - 1);
+    let len = (addr + len).next_multiple_of(PAGE_SIZE) - start;
+    assert!(start + len <= GIGAPAGE_SIZE, "device at {addr:#x} is outside the MMIO region");
+
+    let table = KERNEL_PAGE_TABLE.lock();
+    let table = table.get();
+    unsafe {
+        for offset in (0..len).step_by(PAGE_SIZE) {
+            let page = start + offset;
+            match (*table).map_page(
+                VAddr((MMIO_BASE + page) as *const ()),
+                PAddr(page as *const ()),
+                PAGE_R | PAGE_W | PAGE_G,
+            ) {
+                // Devices can share a page
+                Ok(()) | Err(PagingError::AlreadyMapped) => {}
+                Err(err) => return Err(err),
+            }
+            flush_page(MMIO_BASE + page);
+        }
+    }
+
+    Ok(MMIO_BASE + addr)
+}
+
+/// `satp` value for running on [`KERNEL_PAGE_TABLE`]
+pub fn kernel_satp() -> usize {
+    SATP_SV39_ENABLE | (kernel_page_table() as usize / PAGE_SIZE)
+}
+
+/// Makes sure the root entry covering `vaddr` points to a table. Pages mapped below it later
+/// with [`map_kernel_page`] show up in every address space, even ones created before.
+pub fn reserve_kernel_region(vaddr: usize) -> Result<(), PagingError> {
+    let table = KERNEL_PAGE_TABLE.lock();
+    unsafe { (*table.get()).walk(vaddr, 1, true)? };
+    Ok(())
+}
+
+/// Maps a page into the shared part of the kernel address space, see [`reserve_kernel_region`]
+pub fn map_kernel_page(vaddr: usize, paddr: usize, flags: usize) -> Result<(), PagingError> {
+    let table = KERNEL_PAGE_TABLE.lock();
+    unsafe {
+        (*table.get()).map_page(VAddr(vaddr as *const ()), PAddr(paddr as *const ()), flags | PAGE_G)?;
+    }
+    flush_page(vaddr);
+    Ok(())
+}
+
+pub fn unmap_kernel_page(vaddr: usize) -> Result<PAddr, PagingError> {
+    let table = KERNEL_PAGE_TABLE.lock();
+    let paddr = unsafe { (*table.get()).unmap_page(VAddr(vaddr as *const ()))? };
+    flush_page(vaddr);
+    Ok(paddr)
 }
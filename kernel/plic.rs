@@ -0,0 +1,117 @@
+//! Platform-Level Interrupt Controller
+//!
+//! Routes device interrupts to the boot hart's supervisor context. Drivers [`register`] a
+//! handler for their interrupt source, [`handle_external`] claims whatever is pending and
+//! runs the handler for it.
+
+use ralloc::collections::BTreeMap;
+use utils::sync::Once;
+
+use crate::{dtree::DeviceTree, lock::IrqSpinLock, paging::map_kernel_mmio, traits::KSay};
+
+const PRIORITY: usize = 0x0;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const THRESHOLD: usize = 0x0;
+const CLAIM: usize = 0x4;
+
+static PLIC: Once<Plic> = Once::new();
+static HANDLERS: IrqSpinLock<BTreeMap<u32, fn()>> = IrqSpinLock::new(BTreeMap::new());
+
+pub struct Plic {
+    base: usize,
+    /// Supervisor context of the boot hart
+    context: usize,
+}
+
+impl KSay for Plic {
+    const NAME: &'static str = "plic";
+}
+
+impl Plic {
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base + offset) as *mut u32
+    }
+
+    fn enable_reg(&self, irq: u32) -> *mut u32 {
+        self.reg(ENABLE + ENABLE_STRIDE * self.context + (irq as usize / 32) * 4)
+    }
+
+    fn context_reg(&self, offset: usize) -> *mut u32 {
+        self.reg(CONTEXT + CONTEXT_STRIDE * self.context + offset)
+    }
+
+    fn enable(&self, irq: u32) {
+        unsafe {
+            self.reg(PRIORITY + irq as usize * 4).write_volatile(1);
+            let enable = self.enable_reg(irq);
+            enable.write_volatile(enable.read_volatile() | 1 << (irq % 32));
+        }
+    }
+
+    fn claim(&self) -> u32 {
+        unsafe { self.context_reg(CLAIM).read_volatile() }
+    }
+
+    fn complete(&self, irq: u32) {
+        unsafe { self.context_reg(CLAIM).write_volatile(irq) }
+    }
+}
+
+/// Finds and maps the PLIC
+pub fn init(dtree: &DeviceTree, hart: usize) {
+    let soc = dtree.search("/soc");
+    let node = soc.and_then(|soc| soc.children().iter().find(|node| matches!(node.base_name(), "plic" | "interrupt-controller")));
+    let Some(node) = node else {
+        Plic::kprint("no PLIC in the devicetree, device interrupts are off");
+        return;
+    };
+
+    let cells = soc.and_then(|soc| soc.addr_size_cells()).unwrap_or((2, 1));
+    let Some(&(base, size)) = node.reg(cells).first() else {
+        Plic::kprint("PLIC has no registers");
+        return;
+    };
+    let regs = map_kernel_mmio(base, size).expect("failed to map plic");
+
+    // QEMU's virt machine gives every hart an M-mode context followed by an S-mode one
+    let plic = PLIC.call_once(|| Plic { base: regs, context: hart * 2 + 1 });
+    unsafe { plic.context_reg(THRESHOLD).write_volatile(0) };
+
+    Plic::kprint(format_args!("at {base:#x}, hart {hart} uses context {}", plic.context));
+}
+
+/// Runs `handler` whenever `irq` fires. Returns false if there's no PLIC to deliver it.
+pub fn register(irq: u32, handler: fn()) -> bool {
+    let Some(plic) = PLIC.get() else {
+        return false;
+    };
+
+    HANDLERS.lock().insert(irq, handler);
+    plic.enable(irq);
+    true
+}
+
+/// Supervisor external interrupt
+pub fn handle_external() {
+    let Some(plic) = PLIC.get() else {
+        return;
+    };
+
+    loop {
+        let irq = plic.claim();
+        if irq == 0 {
+            break;
+        }
+
+        let handler = HANDLERS.lock().get(&irq).copied();
+        match handler {
+            Some(handler) => handler(),
+            None => Plic::kprint(format_args!("unexpected interrupt {irq}")),
+        }
+
+        plic.complete(irq);
+    }
+}
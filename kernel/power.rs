@@ -0,0 +1,79 @@
+//! Shutting down and rebooting
+//!
+//! Both go through the SBI system reset extension (SRST). What a panic does is picked with
+//! `panic=halt|reboot|shutdown` on the kernel command line. Halting, the default, leaves the
+//! machine as it is for a debugger, the other two tell the SBI the system failed, so an
+//! automated run exits instead of hanging.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{
+    dtree::DeviceTree,
+    sbi::{self, Extension, ResetReason, ResetType, SbiError, sbi_system_reset},
+    traits::KSay,
+};
+
+static PANIC_POLICY: AtomicU8 = AtomicU8::new(PanicPolicy::Halt as u8);
+
+pub struct Power;
+
+impl KSay for Power {
+    const NAME: &'static str = "power";
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PanicPolicy {
+    /// Every hart waits for interrupts forever
+    Halt,
+    Reboot,
+    Shutdown,
+}
+
+/// Picks the panic policy from the kernel command line
+pub fn init(dtree: &DeviceTree) {
+    let policy = match dtree.bootarg("panic") {
+        None | Some("halt") => PanicPolicy::Halt,
+        Some("reboot") => PanicPolicy::Reboot,
+        Some("shutdown") => PanicPolicy::Shutdown,
+        Some(name) => {
+            Power::kprint(format_args!("unknown panic policy {name:?}, halting on panic"));
+            PanicPolicy::Halt
+        }
+    };
+
+    if policy != PanicPolicy::Halt && !sbi::has(Extension::Srst) {
+        Power::kprint("no SRST extension, panics will halt");
+    }
+    PANIC_POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+pub fn panic_policy() -> PanicPolicy {
+    match PANIC_POLICY.load(Ordering::Relaxed) {
+        policy if policy == PanicPolicy::Reboot as u8 => PanicPolicy::Reboot,
+        policy if policy == PanicPolicy::Shutdown as u8 => PanicPolicy::Shutdown,
+        _ => PanicPolicy::Halt,
+    }
+}
+
+/// Powers the machine off. Only returns if the SBI can't.
+pub fn shutdown(reason: ResetReason) -> SbiError {
+    reset(ResetType::Shutdown, reason)
+}
+
+/// Only returns if the SBI can't reboot
+pub fn reboot(reason: ResetReason) -> SbiError {
+    reset(ResetType::ColdReboot, reason)
+}
+
+fn reset(ty: ResetType, reason: ResetReason) -> SbiError {
+    if !sbi::has(Extension::Srst) {
+        return SbiError::NotSupported;
+    }
+
+    match sbi_system_reset(ty as u32, reason as u32) {
+        // Success never comes back
+        Ok(_) => SbiError::Failed,
+        Err(err) => err,
+    }
+}
@@ -2,6 +2,8 @@ use core::fmt::Write;
 
 use owo_colors::{colors::*, OwoColorize};
 
+use crate::lock::IrqSpinLock;
+
 // Okay so I want SBI putchar as a (blocking) alternative to UART communication
 // But I want every macro to work.
 // Solution:
@@ -11,7 +13,7 @@ use owo_colors::{colors::*, OwoColorize};
 // implementation for writing.
 pub struct SbiPrinter;
 
-pub trait Printer: Write {
+pub trait Printer: Write + Send {
     fn name(&self) -> &str;
 }
 
@@ -23,8 +25,9 @@ impl Printer for SbiPrinter {
 
 static mut SBI_PRINTER: SbiPrinter = SbiPrinter;
 
-/// NOTE: DO NOT USE
-pub static mut PRINTER: &mut dyn Printer = unsafe { &mut SBI_PRINTER as &mut dyn Printer };
+/// NOTE: DO NOT USE, go through the macros. Printing from inside a print (a `Display` impl
+/// that prints, say) deadlocks.
+pub static PRINTER: IrqSpinLock<&mut dyn Printer> = IrqSpinLock::new(unsafe { &mut SBI_PRINTER as &mut dyn Printer });
 
 pub fn set_printer(printer: &'static mut dyn Printer) {
     crate::println!(
@@ -32,15 +35,13 @@ pub fn set_printer(printer: &'static mut dyn Printer) {
         "printer".fg::<Yellow>(),
         printer.name().fg::<BrightCyan>()
     );
-    unsafe {
-        PRINTER = printer;
-    }
+    *PRINTER.lock() = printer;
 }
 
 impl core::fmt::Write for SbiPrinter {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         for b in s.bytes() {
-            crate::sbi::sbi_putchar(b);
+            crate::sbi::sbi_putchar(b).map_err(|_| core::fmt::Error)?;
         }
         Ok(())
     }
@@ -53,7 +54,7 @@ macro_rules! println {
         unsafe {
             #[allow(unused)]
             use ::core::fmt::Write;
-            let _ = writeln!($crate::print::PRINTER, $($arg)*);
+            let _ = writeln!($crate::print::PRINTER.lock(), $($arg)*);
         }
     };
 }
@@ -65,7 +66,7 @@ macro_rules! print {
         unsafe {
             #[allow(unused)]
             use ::core::fmt::Write;
-            let _ = write!($crate::print::PRINTER, $($arg)*);
+            let _ = write!($crate::print::PRINTER.lock(), $($arg)*);
         }
     };
 }
@@ -1,22 +1,74 @@
-use core::{arch::naked_asm, slice};
+use core::{arch::naked_asm, hint::spin_loop, mem, slice, sync::atomic::{AtomicBool, Ordering}};
+
+use ralloc::{collections::BTreeMap, vec::Vec};
 
 use crate::{
-    __heap_end, __kernel_base, PROC_CURR, PROC_IDLE, alloc::GLOBAL_ALLOC, paging::{
-        PAGE_R, PAGE_SIZE, PAGE_U, PAGE_W, PAGE_X, PAddr, PageTable, SATP_SV39_ENABLE, VAddr
-    }, switch_page_table, user::{USER_BASE, userspace_entry}, virtio::VIRTIO_BLK_PADDR, write_csr
+    asid::ASIDS, lock::IrqSpinLock, hart::{self, curr_proc, hart_id, this_hart}, kstack::{KernelStack, KstackError}, println, sched::{NICE_MAX, NICE_MIN, sched}, paging::{
+        kernel_page_table, PAGE_R, PAGE_SIZE, PAGE_W, PageTable, PagingError, kernel_satp
+    }, elf::{Elf, ElfError}, ext2::{FS, FsError}, slab::Cache, timer, wait::WaitQueue, trap::{TRAP_FRAME_WORDS, TrapFrame, trap_return}, user::{
+        USER_HEAP_SIZE, USER_STACK_SIZE, USER_STACK_TOP, init_stack, userspace_entry
+    },
+    vma::{Backing, Vma, VmaError, VmaKind, VmaList}, write_csr
 };
 
-const PROC_MAX: usize = 0x16;
-const STACK_SIZE: usize = 0x2000;
-pub static mut PROCS: [Process; PROC_MAX] = [Process::default(); PROC_MAX];
+/// Exit status of processes killed by the kernel, 128 + SIGSEGV like a shell would report it
+pub const KILLED_STATUS: usize = 139;
+/// Most bytes of arguments and environment passed to a new image, the rest of the initial
+/// stack area is left for the program headers, auxiliary vector and the program itself
+const ARG_MAX: usize = USER_STACK_SIZE / 2;
+pub static PROCS: IrqSpinLock<ProcTable> = IrqSpinLock::new(ProcTable::new());
+pub static PROC_CACHE: Cache<Process> = Cache::new("process");
+/// Every hart's idle process has this pid, they run whenever nothing else can and are never
+/// queued or in [`PROCS`]
+const IDLE_PID: usize = 0;
+/// Woken whenever a zombie may have no parent to wait for it, see [`reaper`]
+static REAPER_WAIT: WaitQueue = WaitQueue::new();
+
+/// Every live process (and zombie) by pid. Don't hold the lock across a context switch.
+pub struct ProcTable {
+    procs: BTreeMap<usize, *mut Process>,
+    /// Pids are never reused
+    next_pid: usize,
+}
+
+// Processes are only reachable through the table's lock
+unsafe impl Send for ProcTable {}
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Debug)]
 pub struct Process {
     pub(crate) pid: usize,
     pub(crate) state: ProcessState,
     pub(crate) sp: usize,
     pub(crate) page_table: *mut PageTable,
-    pub(crate) kstack: [u8; STACK_SIZE],
+    /// Where the process starts running in user mode
+    pub(crate) entry: usize,
+    /// User stack pointer the process starts with
+    pub(crate) user_sp: usize,
+    /// `None` for processes the kernel started, or whose parent already exited
+    pub(crate) parent: Option<usize>,
+    pub(crate) exit_status: usize,
+    /// Tagged with the generation it was handed out in, see [`crate::asid`]
+    pub(crate) asid: usize,
+    /// Ticks left of the current time slice
+    pub(crate) slice: usize,
+    /// Ticks spent running in total
+    pub(crate) ticks: usize,
+    /// From [`NICE_MIN`] to [`NICE_MAX`], lower gets more CPU time
+    pub(crate) nice: isize,
+    /// Weighted ticks spent running, see [`crate::sched::Fair`]
+    pub(crate) vruntime: usize,
+    /// Woken whenever a child exits, see [`wait`]
+    pub(crate) child_exit: WaitQueue,
+    /// Picked by a hart and not yet switched away from. Only changes with the scheduler lock
+    /// held, a process woken while it's set puts itself back on the run queue.
+    pub(crate) on_hart: bool,
+    /// Set until the switch away from it is finished, nothing else may run on (or free) its
+    /// kernel stack before then
+    pub(crate) running: AtomicBool,
+    /// Hart it last ran on, see [`crate::asid`]
+    pub(crate) last_hart: usize,
+    pub(crate) vmas: VmaList,
+    pub(crate) kstack: KernelStack,
 }
 
 impl PartialEq for Process {
@@ -27,16 +79,46 @@ impl PartialEq for Process {
 
 impl Eq for Process {}
 
-impl const Default for Process {
-    fn default() -> Self {
-        Self {
-            pid: usize::MAX,
-            state: ProcessState::Unused,
-            sp: usize::MAX,
-            page_table: core::ptr::null_mut(),
-            kstack: [0; STACK_SIZE],
+impl Process {
+    pub fn kstack_top(&self) -> usize {
+        self.kstack.top()
+    }
+
+    /// Kernel threads run on the kernel page table, they have no user space
+    pub fn is_kernel_thread(&self) -> bool {
+        self.page_table == kernel_page_table()
+    }
+}
+
+impl ProcTable {
+    const fn new() -> Self {
+        ProcTable {
+            procs: BTreeMap::new(),
+            next_pid: IDLE_PID + 1,
         }
     }
+
+    pub fn get(&self, pid: usize) -> Option<*mut Process> {
+        self.procs.get(&pid).copied()
+    }
+
+    /// Gives `proc` the next pid and adds it to the table
+    fn insert(&mut self, proc: *mut Process) -> usize {
+        let pid = self.next_pid;
+        self.next_pid += 1;
+
+        unsafe { (*proc).pid = pid };
+        self.procs.insert(pid, proc);
+        pid
+    }
+
+    fn remove(&mut self, pid: usize) -> Option<*mut Process> {
+        self.procs.remove(&pid)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = *mut Process> + '_ {
+        self.procs.values().copied()
+    }
 }
 
 #[repr(usize)]
@@ -44,7 +126,10 @@ impl const Default for Process {
 pub enum ProcessState {
     Unused,
     InUse,
-    Exited,
+    /// Sleeping on a [`WaitQueue`], not on the run queue until it's woken
+    Blocked,
+    /// Exited, kept around until its parent collects the exit status with [`wait`]
+    Zombie,
 }
 
 impl const Default for ProcessState {
@@ -56,100 +141,635 @@ impl const Default for ProcessState {
 #[repr(u8)]
 #[derive(Debug)]
 pub enum ProcessError {
+    /// No room left for another kernel stack
     MaxProcsUsed = 0,
+    OutOfMemory = 1,
+    Paging(PagingError) = 2,
+    Vma(VmaError) = 3,
+    Elf(ElfError) = 4,
+    Fs(FsError) = 5,
+    /// `argv` and `envp` together are larger than [`ARG_MAX`]
+    ArgsTooLong = 6,
+    /// Nothing to wait for
+    NoChild = 7,
+    NoSuchProcess = 8,
 }
 
-pub fn r#yield() {
-    let mut next = unsafe { *PROC_IDLE };
+impl From<PagingError> for ProcessError {
+    fn from(err: PagingError) -> Self {
+        ProcessError::Paging(err)
+    }
+}
+
+impl From<ElfError> for ProcessError {
+    fn from(err: ElfError) -> Self {
+        ProcessError::Elf(err)
+    }
+}
 
-    for i in 0..PROC_MAX {
-        let proc = unsafe { &raw mut PROCS[((*PROC_CURR.unwrap()).pid + i + 1) % PROC_MAX] };
-        if unsafe { (*proc).state == ProcessState::InUse && (*proc).pid > 0 } {
-            next = proc;
-            break;
+impl From<FsError> for ProcessError {
+    fn from(err: FsError) -> Self {
+        ProcessError::Fs(err)
+    }
+}
+
+impl From<KstackError> for ProcessError {
+    fn from(err: KstackError) -> Self {
+        match err {
+            KstackError::NoSlots => ProcessError::MaxProcsUsed,
+            KstackError::Paging(err) => ProcessError::Paging(err),
         }
     }
+}
+
+impl From<VmaError> for ProcessError {
+    fn from(err: VmaError) -> Self {
+        ProcessError::Vma(err)
+    }
+}
 
-    if unsafe { next == PROC_CURR.unwrap() } {
+pub fn r#yield() {
+    let hart = this_hart();
+    let curr = unsafe { &mut *hart.curr.unwrap() };
+    let next = {
+        let procs = PROCS.lock();
+        let mut sched = sched();
+        curr.on_hart = false;
+        if curr.state == ProcessState::InUse && curr.pid != IDLE_PID {
+            sched.enqueue(curr);
+        }
+
+        let next = sched.pick_next().and_then(|pid| procs.get(pid)).unwrap_or(hart.idle);
+        unsafe { (*next).on_hart = true };
+        hart::mark_idle(next == hart.idle);
+        next
+    };
+
+    hart.need_resched = false;
+    unsafe { (*next).slice = timer::quantum() };
+
+    if next == curr as *mut Process {
         // Do nothing
         return;
     }
 
     unsafe {
-        switch_page_table!(SATP_SV39_ENABLE | ((*next).page_table as usize / PAGE_SIZE));
+        // It may have been put back on the run queue by a hart that's still switching away
+        // from it
+        while (*next).running.swap(true, Ordering::Acquire) {
+            spin_loop();
+        }
+
+        let migrated = (*next).last_hart != hart_id();
+        (*next).last_hart = hart_id();
+
+        if (*next).is_kernel_thread() {
+            // Kernel mappings are global, nothing to flush
+            write_csr!("satp", kernel_satp());
+        } else {
+            ASIDS.lock().switch_to(&mut (*next).asid, (*next).page_table, migrated);
+        }
+
+        hart.prev = Some(curr);
+        hart.curr = Some(next);
+        switch_context(&raw mut curr.sp, &raw mut (*next).sp);
+    }
 
-        write_csr!(
-            "sscratch",
-            (*next).kstack.as_mut_ptr().add(STACK_SIZE) as usize
-        );
+    // Possibly on another hart by now
+    finish_switch();
+}
+
+/// Runs on the new process's stack right after every switch. The previous process is off
+/// its kernel stack now, so other harts may run it, or reap it if it exited.
+pub extern "C" fn finish_switch() {
+    let Some(prev) = this_hart().prev.take() else {
+        return;
+    };
+
+    let (zombie, parent) = unsafe { ((*prev).state == ProcessState::Zombie, (*prev).parent) };
+    unsafe { (*prev).running.store(false, Ordering::Release) };
+    if !zombie {
+        return;
+    }
+
+    // The parent can't be reaped while the table is locked, but waking it takes the lock
+    let waiters = {
+        let procs = PROCS.lock();
+        parent.and_then(|pid| procs.get(pid)).map(|parent| unsafe { (*parent).child_exit.take_waiters() })
+    };
+    for pid in waiters.into_iter().flatten() {
+        wake(pid);
+    }
+    // Either the zombie or children that exited before it may be orphans now
+    REAPER_WAIT.wake_all();
+}
+
+pub fn is_idle(proc: *mut Process) -> bool {
+    unsafe { (*proc).pid == IDLE_PID }
+}
+
+/// Marks the current process as blocked, it's taken off the run queue by the next [`block`]
+/// unless [`wake`] is called for it first. Use a [`WaitQueue`] rather than calling this
+/// directly.
+pub fn prepare_block() {
+    let curr = unsafe { &mut *curr_proc().unwrap() };
+    assert!(curr.pid != IDLE_PID, "the idle process can't block");
+
+    let _sched = sched();
+    curr.state = ProcessState::Blocked;
+}
+
+/// Undoes [`prepare_block`] once there's no need to block after all
+pub fn cancel_block() {
+    let curr = unsafe { &mut *curr_proc().unwrap() };
+
+    let _sched = sched();
+    if curr.state == ProcessState::Blocked {
+        curr.state = ProcessState::InUse;
+    }
+}
+
+/// Switches away from the current process after [`prepare_block`], it doesn't run again
+/// until it's woken
+pub fn block() {
+    r#yield();
+}
+
+/// Makes a blocked process runnable again, does nothing if it isn't blocked
+pub fn wake(pid: usize) {
+    let procs = PROCS.lock();
+    let Some(proc) = procs.get(pid) else {
+        return;
+    };
+
+    let proc = unsafe { &mut *proc };
+    let mut sched = sched();
+    if proc.state == ProcessState::Blocked {
+        proc.state = ProcessState::InUse;
+        // Otherwise it hasn't switched away yet and queues itself when it does
+        if !proc.on_hart {
+            sched.enqueue(proc);
+            hart::kick_idle();
+        }
+    }
+}
+
+/// Charges a timer tick to the process running on this hart
+pub fn tick() {
+    let hart = this_hart();
+    let Some(proc) = hart.curr else {
+        return;
+    };
+
+    let proc = unsafe { &mut *proc };
+    proc.ticks += 1;
+    if proc.pid == IDLE_PID || sched().tick(proc) {
+        hart.need_resched = true;
+    }
+}
+
+/// Sets the nice value of `pid`, clamped to [`NICE_MIN`]..=[`NICE_MAX`]. Returns the value
+/// actually set.
+pub fn set_nice(pid: usize, nice: isize) -> Result<isize, ProcessError> {
+    let nice = nice.clamp(NICE_MIN, NICE_MAX);
+    let procs = PROCS.lock();
+    let proc = procs
+        .get(pid)
+        .filter(|_| pid != IDLE_PID)
+        .map(|proc| unsafe { &mut *proc })
+        .ok_or(ProcessError::NoSuchProcess)?;
+
+    let mut sched = sched();
+    match proc.state {
+        ProcessState::Unused | ProcessState::Zombie => return Err(ProcessError::NoSuchProcess),
+        // The run queue may be ordered by it
+        ProcessState::InUse if !proc.on_hart => {
+            sched.remove(proc);
+            proc.nice = nice;
+            sched.enqueue(proc);
+        }
+        // Not on the run queue
+        ProcessState::InUse | ProcessState::Blocked => proc.nice = nice,
+    }
+
+    Ok(nice)
+}
+
+/// Switches away from the current process if its time slice ran out. Only safe where a
+/// context switch is, like on the way back to user mode.
+pub fn preempt() {
+    if this_hart().need_resched {
+        r#yield();
+    }
+}
+
+/// Marks the current process as a zombie and switches away from it for good. User memory is
+/// released right away, the rest once the parent collects `status`. Whoever reaps it is woken
+/// once it's off its kernel stack, see [`finish_switch`].
+pub fn exit_current(status: usize) {
+    let curr_proc: &mut Process = unsafe { curr_proc().unwrap().as_mut().unwrap() };
+    println!("Process exiting: {} (status {})", curr_proc.pid, status);
+
+    for child in PROCS.lock().iter() {
+        let child = unsafe { &mut *child };
+        if child.parent == Some(curr_proc.pid) {
+            child.parent = None;
+        }
+    }
 
-        let prev = PROC_CURR.unwrap();
-        PROC_CURR = Some(next);
-        switch_context(&raw mut (*prev).sp, &raw mut (*next).sp);
+    if !curr_proc.is_kernel_thread() {
+        unsafe { (*curr_proc.page_table).unmap_user() };
+        ASIDS.lock().free(curr_proc.asid);
     }
+    curr_proc.exit_status = status;
+    curr_proc.state = ProcessState::Zombie;
+
+    r#yield();
 }
 
-pub fn create_process(image: *mut u8, size: usize) -> Result<*mut Process, ProcessError> {
-    let mut index = None;
-    for i in 0..PROC_MAX {
-        if unsafe { PROCS[i] }.state == ProcessState::Unused {
-            index = Some(i);
-            break;
+/// Frees everything left of a zombie and removes it from [`PROCS`]
+///
+/// # Safety
+/// `proc` must be a zombie that's no longer [`Process::running`]
+unsafe fn reap(proc: *mut Process) {
+    unsafe {
+        PROCS.lock().remove((*proc).pid);
+        if !(*proc).is_kernel_thread() {
+            PageTable::free((*proc).page_table);
         }
+        core::ptr::drop_in_place(&raw mut (*proc).vmas);
+        core::ptr::drop_in_place(&raw mut (*proc).child_exit);
+        core::ptr::read(&raw const (*proc).kstack).free();
+        PROC_CACHE.free(proc).expect("process was not allocated from the cache");
+    }
+}
+
+/// Kernel thread reaping the zombies nobody will wait for, it can only run once they're off
+/// their kernel stacks
+fn reaper(_: usize) {
+    loop {
+        let orphan = REAPER_WAIT.wait_until(|| {
+            PROCS
+                .lock()
+                .iter()
+                .find(|&proc| unsafe { is_dead(proc) && (*proc).parent.is_none() })
+        });
+
+        unsafe { reap(orphan) };
     }
+}
+
+/// Zombie that's off its kernel stack, ready to be reaped
+fn is_dead(proc: *mut Process) -> bool {
+    unsafe { (*proc).state == ProcessState::Zombie && !(*proc).running.load(Ordering::Acquire) }
+}
+
+/// Starts the kernel threads every system has, the idle process has to exist already
+pub fn spawn_kernel_threads() {
+    spawn_kernel_thread(reaper, 0).expect("failed to start the reaper");
+}
 
-    index
-        .map(|proc| unsafe {
-            let ptr = &raw mut PROCS[proc];
-            let mut sp = &raw mut PROCS[proc].kstack[STACK_SIZE - 8] as *mut usize;
+/// Waits for a child of the current process to exit, `pid` picks a specific one.
+/// Reaps it and returns its pid and exit status.
+pub fn wait(pid: Option<usize>) -> Result<(usize, usize), ProcessError> {
+    let curr = unsafe { &*curr_proc().unwrap() };
 
-            // s11-s0
-            for _ in 0..12 {
-                sp.write(0);
-                sp = sp.wrapping_sub(1);
+    let zombie = curr.child_exit.wait_until(|| {
+        let procs = PROCS.lock();
+        let is_child = |&proc: &*mut Process| unsafe { (*proc).parent == Some(curr.pid) };
+        let is_zombie = |&proc: &*mut Process| is_dead(proc);
+
+        match pid {
+            Some(pid) => match procs.get(pid).filter(is_child) {
+                Some(child) => Some(Ok(child)).filter(|_| is_zombie(&child)),
+                None => Some(Err(ProcessError::NoChild)),
+            },
+            None => {
+                let mut children = procs.iter().filter(is_child).peekable();
+                if children.peek().is_none() {
+                    return Some(Err(ProcessError::NoChild));
+                }
+                children.find(is_zombie).map(Ok)
             }
+        }
+    })?;
+
+    let (pid, status) = unsafe { ((*zombie).pid, (*zombie).exit_status) };
+    unsafe { reap(zombie) };
+    Ok((pid, status))
+}
+
+/// Duplicates the current process. The child shares every user page copy-on-write and
+/// resumes from a copy of `f`, seeing 0 as the result of the syscall.
+pub fn fork(f: &TrapFrame) -> Result<usize, ProcessError> {
+    let parent = unsafe { &mut *curr_proc().unwrap() };
+
+    let kstack = KernelStack::alloc()?;
+    let ptr = match PROC_CACHE.alloc_zeroed() {
+        Ok(ptr) => ptr,
+        Err(_) => {
+            unsafe { kstack.free() };
+            return Err(ProcessError::OutOfMemory);
+        }
+    };
+
+    let page_table = match unsafe { (*parent.page_table).fork_user() } {
+        Ok(page_table) => page_table,
+        Err(err) => {
+            unsafe {
+                kstack.free();
+                PROC_CACHE.free(ptr).expect("process was just allocated");
+            }
+            return Err(err.into());
+        }
+    };
+
+    unsafe {
+        (&raw mut (*ptr).vmas).write(parent.vmas.clone());
+        (&raw mut (*ptr).child_exit).write(WaitQueue::new());
+        (&raw mut (*ptr).kstack).write(kstack);
+
+        // Copy of the parent's trap frame, where the child's kstack would have it
+        let frame = ((*ptr).kstack_top() - TRAP_FRAME_WORDS * 8) as *mut TrapFrame;
+        core::ptr::copy_nonoverlapping(f, frame, 1);
+        (*frame).a0 = 0;
+        (*frame).a1 = 0;
+
+        // switch_context pops ra and s0-s11, then "returns" into fork_return
+        let sp = (frame as *mut usize).sub(13);
+        sp.write(fork_return as *const () as usize);
+        for i in 1..13 {
+            sp.add(i).write(0);
+        }
+
+        (*ptr).parent = Some(parent.pid);
+        (*ptr).state = ProcessState::InUse;
+        (*ptr).page_table = page_table;
+        (*ptr).sp = sp as usize;
+        (*ptr).nice = parent.nice;
+    }
+
+    let pid = PROCS.lock().insert(ptr);
+    sched().enqueue(unsafe { &mut *ptr });
+    hart::kick_idle();
+    Ok(pid)
+}
+
+/// Replaces the current process's image with the ELF at `path` on the filesystem. On
+/// success `f` is reset to start the new image, on failure the old image is untouched.
+/// `argv` and `envp` must already be copied out of the old address space.
+pub fn exec(f: &mut TrapFrame, path: &[u8], argv: &[Vec<u8>], envp: &[Vec<u8>]) -> Result<(), ProcessError> {
+    let proc = unsafe { &mut *curr_proc().unwrap() };
+
+    // Each string also needs its NUL and a pointer to it
+    let arg_bytes: usize = argv.iter().chain(envp).map(|arg| arg.len() + 1 + size_of::<usize>()).sum();
+    if arg_bytes > ARG_MAX {
+        return Err(ProcessError::ArgsTooLong);
+    }
 
-            // set ra to pc
-            sp.write(userspace_entry as *const () as usize);
+    let image = FS.get().expect("filesystem is not mounted").read_path(path)?;
 
-            let page_table = PageTable::alloc();
+    let page_table = PageTable::new_user()?;
+    let mut vmas = VmaList::new();
+    let argv: Vec<&[u8]> = argv.iter().map(Vec::as_slice).collect();
+    let envp: Vec<&[u8]> = envp.iter().map(Vec::as_slice).collect();
 
-            let mut addr = &raw mut __kernel_base;
-            while addr < &raw mut __heap_end {
-                (*page_table).map_page(
-                    VAddr(addr as *mut ()),
-                    PAddr(addr as *mut ()),
-                    PAGE_X | PAGE_R | PAGE_W,
-                );
-                addr = addr.add(PAGE_SIZE);
+    let (entry, sp) = match load_image(unsafe { &mut *page_table }, &mut vmas, &image, &argv, &envp) {
+        Ok(start) => start,
+        Err(err) => {
+            unsafe {
+                (*page_table).unmap_user();
+                PageTable::free(page_table);
             }
+            return Err(err);
+        }
+    };
+
+    // No going back from here, the old image is gone
+    let old = core::mem::replace(&mut proc.page_table, page_table);
+    proc.vmas = vmas;
+    proc.entry = entry;
+    proc.user_sp = sp;
 
-            (*page_table).map_page(VAddr(VIRTIO_BLK_PADDR as *mut ()), PAddr(VIRTIO_BLK_PADDR as *mut ()), PAGE_R | PAGE_W);
+    {
+        // The old ASID may still have the old image's entries in the TLB
+        let mut asids = ASIDS.lock();
+        asids.free(proc.asid);
+        proc.asid = 0;
+        asids.switch_to(&mut proc.asid, page_table, false);
+    }
 
-            for offset in (0..size).step_by(PAGE_SIZE) {
-                let page = GLOBAL_ALLOC.alloc_page();
+    unsafe {
+        (*old).unmap_user();
+        PageTable::free(old);
+    }
 
-                let rem = size - offset;
-                let copy_size = if PAGE_SIZE <= rem { PAGE_SIZE } else { rem };
+    let sstatus = f.sstatus;
+    unsafe { core::ptr::write_bytes(f as *mut TrapFrame, 0, 1) };
+    f.sstatus = sstatus;
+    f.sepc = entry;
+    f.sp = sp;
 
-                slice::from_raw_parts_mut(page, copy_size)
-                    .copy_from_slice(slice::from_raw_parts(image.add(offset), copy_size));
+    Ok(())
+}
+
+pub fn create_process(image: *mut u8, size: usize, argv: &[&[u8]]) -> Result<*mut Process, ProcessError> {
+    let kstack = KernelStack::alloc()?;
+
+    // Zeroed avoids building a process on the stack
+    let ptr = match PROC_CACHE.alloc_zeroed() {
+        Ok(ptr) => ptr,
+        Err(_) => {
+            unsafe { kstack.free() };
+            return Err(ProcessError::OutOfMemory);
+        }
+    };
+
+    // Kernel mappings are shared, only user space is per process
+    let page_table = match PageTable::new_user() {
+        Ok(page_table) => page_table,
+        Err(err) => {
+            unsafe {
+                kstack.free();
+                PROC_CACHE.free(ptr).expect("process was just allocated");
+            }
+            return Err(err.into());
+        }
+    };
 
-                (*page_table).map_page(
-                    VAddr((USER_BASE + offset) as *mut ()),
-                    PAddr(page as *mut ()),
-                    PAGE_R | PAGE_W | PAGE_X | PAGE_U,
-                );
+    let mut vmas = VmaList::new();
+    let (entry, user_sp) = match size {
+        0 => (0, 0),
+        _ => match load_image(
+            unsafe { &mut *page_table },
+            &mut vmas,
+            unsafe { slice::from_raw_parts(image, size) },
+            argv,
+            &[],
+        ) {
+            Ok(start) => start,
+            Err(err) => {
+                unsafe {
+                    kstack.free();
+                    PROC_CACHE.free(ptr).expect("process was just allocated");
+                    (*page_table).unmap_user();
+                    PageTable::free(page_table);
+                }
+                return Err(err);
             }
+        },
+    };
+
+    unsafe {
+        // Not valid when zeroed
+        (&raw mut (*ptr).vmas).write(vmas);
+        (&raw mut (*ptr).child_exit).write(WaitQueue::new());
+        (&raw mut (*ptr).kstack).write(kstack);
+
+        // switch_context pops ra and s0-s11 into userspace_entry, the trap frame of the first
+        // trap from user mode goes above that
+        let sp = ((*ptr).kstack_top() - (TRAP_FRAME_WORDS + 13) * 8) as *mut usize;
+        sp.write(userspace_entry as *const () as usize);
+        for i in 1..13 {
+            sp.add(i).write(0);
+        }
+
+        (*ptr).entry = entry;
+        (*ptr).user_sp = user_sp;
+        (*ptr).state = ProcessState::InUse;
+        (*ptr).page_table = page_table;
+        (*ptr).sp = sp as usize;
+
+        PROCS.lock().insert(ptr);
+        sched().enqueue(&mut *ptr);
+        hart::kick_idle();
+
+        Ok(ptr)
+    }
+}
+
+/// Creates an idle process for a hart. It runs on the kernel page table and has no context
+/// to start from, the hart is already running it when it first switches away.
+pub fn create_idle() -> Result<*mut Process, ProcessError> {
+    let kstack = KernelStack::alloc()?;
+    let ptr = match PROC_CACHE.alloc_zeroed() {
+        Ok(ptr) => ptr,
+        Err(_) => {
+            unsafe { kstack.free() };
+            return Err(ProcessError::OutOfMemory);
+        }
+    };
+
+    unsafe {
+        (&raw mut (*ptr).vmas).write(VmaList::new());
+        (&raw mut (*ptr).child_exit).write(WaitQueue::new());
+        (&raw mut (*ptr).kstack).write(kstack);
+
+        (*ptr).pid = IDLE_PID;
+        (*ptr).state = ProcessState::InUse;
+        (*ptr).page_table = kernel_page_table();
+    }
+
+    Ok(ptr)
+}
+
+/// Starts `entry(arg)` in a kernel thread, running in S-mode on the kernel page table.
+/// Like all kernel code it's never preempted, so it has to block or yield every now and
+/// then. Returning from `entry` exits the thread. Returns its pid.
+pub fn spawn_kernel_thread(entry: fn(usize), arg: usize) -> Result<usize, ProcessError> {
+    let kstack = KernelStack::alloc()?;
+    let ptr = match PROC_CACHE.alloc_zeroed() {
+        Ok(ptr) => ptr,
+        Err(_) => {
+            unsafe { kstack.free() };
+            return Err(ProcessError::OutOfMemory);
+        }
+    };
+
+    unsafe {
+        (&raw mut (*ptr).vmas).write(VmaList::new());
+        (&raw mut (*ptr).child_exit).write(WaitQueue::new());
+        (&raw mut (*ptr).kstack).write(kstack);
+
+        // switch_context pops ra and s0-s11, kernel_thread_start finds the entry in s0
+        let sp = ((*ptr).kstack_top() - 13 * 8) as *mut usize;
+        sp.write(kernel_thread_start as *const () as usize);
+        sp.add(1).write(entry as usize);
+        sp.add(2).write(arg);
+        for i in 3..13 {
+            sp.add(i).write(0);
+        }
+
+        (*ptr).state = ProcessState::InUse;
+        (*ptr).page_table = kernel_page_table();
+        (*ptr).sp = sp as usize;
+    }
+
+    let pid = PROCS.lock().insert(ptr);
+    sched().enqueue(unsafe { &mut *ptr });
+    hart::kick_idle();
+    Ok(pid)
+}
+
+/// Where forked processes first run, on top of their copy of the parent's trap frame
+#[unsafe(naked)]
+extern "C" fn fork_return() {
+    naked_asm!(
+        "call {finish}",
+        "j {ret}",
+        finish = sym finish_switch,
+        ret = sym trap_return,
+    )
+}
+
+#[unsafe(naked)]
+extern "C" fn kernel_thread_start() {
+    naked_asm!(
+        "mv a0, s0",
+        "mv a1, s1",
+        "tail {main}",
+        main = sym kernel_thread_main,
+    )
+}
+
+extern "C" fn kernel_thread_main(entry: usize, arg: usize) -> ! {
+    finish_switch();
+
+    let entry: fn(usize) = unsafe { mem::transmute(entry) };
+    entry(arg);
+
+    exit_current(0);
+    unreachable!("exited kernel thread was scheduled again");
+}
+
+/// Loads an ELF executable and sets up the heap and stack areas around it.
+/// Returns the entry point and initial stack pointer.
+fn load_image(
+    page_table: &mut PageTable,
+    vmas: &mut VmaList,
+    image: &[u8],
+    argv: &[&[u8]],
+    envp: &[&[u8]],
+) -> Result<(usize, usize), ProcessError> {
+    let elf = Elf::parse(image)?;
+    let image_end = elf.load(page_table, vmas)?;
+
+    // Leave a guard page after the image
+    let heap = vmas.find_free(image_end + PAGE_SIZE, USER_HEAP_SIZE);
+    vmas.insert(Vma::new(heap, heap + USER_HEAP_SIZE, PAGE_R | PAGE_W, VmaKind::Heap, Backing::Anon))?;
 
-            (*ptr).pid = proc;
-            (*ptr).state = ProcessState::InUse;
-            (*ptr).page_table = page_table;
-            (*ptr).sp = sp as usize;
+    let stack = Vma::new(
+        USER_STACK_TOP - USER_STACK_SIZE,
+        USER_STACK_TOP,
+        PAGE_R | PAGE_W,
+        VmaKind::Stack,
+        Backing::Anon,
+    );
+    vmas.insert(stack)?;
+    let phdrs = elf.program_header_bytes()?;
+    let sp = init_stack(page_table, &stack, &elf, phdrs, argv, envp)?;
 
-            ptr
-        })
-        .ok_or_else(|| ProcessError::MaxProcsUsed)
+    Ok((elf.entry(), sp))
 }
 
 #[unsafe(naked)]
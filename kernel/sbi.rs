@@ -1,7 +1,23 @@
 #![expect(unused)]
 
-use core::arch::asm;
+use core::{
+    arch::asm,
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
+use crate::traits::KSay;
+
+/// Extensions [`init`] found, a bit per [`Extension`] index
+static AVAILABLE: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Sbi;
+
+impl KSay for Sbi {
+    const NAME: &'static str = "sbi";
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(i64)]
 #[rustfmt::skip]
 pub enum ImplId {
@@ -12,8 +28,26 @@ pub enum ImplId {
     RustSBI            = 4,
     Diosix             = 5,
     Coffer             = 6,
+    Xen                = 7,
+    PolarFireHSS       = 8,
+    Coreboot           = 9,
+    Oreboot            = 10,
+    Bhyve              = 11,
+}
+
+impl ImplId {
+    pub fn from_id(id: i64) -> Option<ImplId> {
+        use ImplId::*;
+        [
+            BerkeleyBootLoader, OpenSBI, Xvisor, KVM, RustSBI, Diosix, Coffer, Xen, PolarFireHSS,
+            Coreboot, Oreboot, Bhyve,
+        ]
+        .into_iter()
+        .find(|&imp| imp as i64 == id)
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(i64)]
 #[rustfmt::skip]
 pub enum HartState {
@@ -26,24 +60,162 @@ pub enum HartState {
     ResumePending  = 6,
 }
 
-#[repr(C, i64)]
+/// What [`sbi_system_reset`] does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+#[rustfmt::skip]
+pub enum ResetType {
+    Shutdown   = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+/// Why [`sbi_system_reset`] was called, the SBI may pass it on (QEMU exits with a failure)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+#[rustfmt::skip]
+pub enum ResetReason {
+    NoReason      = 0,
+    SystemFailure = 1,
+}
+
+/// Error codes an SBI call can fail with. Codes newer than this kernel end up in
+/// [`SbiError::Unknown`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[rustfmt::skip]
-pub enum SbiRet {
-    SbiSuccess { value: i64 } =  0,
-    SbiErrFailed              = -1,
-    SbiErrNotSupported        = -2,
-    SbiErrInvalidParam        = -3,
-    SbiErrDenied              = -4,
-    SbiErrInvalidAddress      = -5,
-    SbiErrAlreadyAvailable    = -6,
-    SbiErrAlreadyStarted      = -7,
-    SbiErrAlreadyStopped      = -8,
+pub enum SbiError {
+    Failed,
+    NotSupported,
+    InvalidParam,
+    Denied,
+    InvalidAddress,
+    AlreadyAvailable,
+    AlreadyStarted,
+    AlreadyStopped,
+    NoShmem,
+    InvalidState,
+    BadRange,
+    Timeout,
+    Io,
+    Unknown(i64),
 }
 
-#[repr(C)]
-pub struct SbiRetInto {
-    error: i64,
-    value: i64,
+impl SbiError {
+    #[rustfmt::skip]
+    fn from_code(code: i64) -> SbiError {
+        match code {
+            -1  => SbiError::Failed,
+            -2  => SbiError::NotSupported,
+            -3  => SbiError::InvalidParam,
+            -4  => SbiError::Denied,
+            -5  => SbiError::InvalidAddress,
+            -6  => SbiError::AlreadyAvailable,
+            -7  => SbiError::AlreadyStarted,
+            -8  => SbiError::AlreadyStopped,
+            -9  => SbiError::NoShmem,
+            -10 => SbiError::InvalidState,
+            -11 => SbiError::BadRange,
+            -12 => SbiError::Timeout,
+            -13 => SbiError::Io,
+            _   => SbiError::Unknown(code),
+        }
+    }
+}
+
+/// Extensions probed at boot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+#[rustfmt::skip]
+pub enum Extension {
+    Time   = 0x54494D45,
+    Ipi    = 0x735049,
+    Rfence = 0x52464E43,
+    Hsm    = 0x48534D,
+    Srst   = 0x53525354,
+    Dbcn   = 0x4442434E,
+    Pmu    = 0x504D55,
+    Susp   = 0x53555350,
+}
+
+impl Extension {
+    const ALL: [Extension; 8] = [
+        Extension::Time,
+        Extension::Ipi,
+        Extension::Rfence,
+        Extension::Hsm,
+        Extension::Srst,
+        Extension::Dbcn,
+        Extension::Pmu,
+        Extension::Susp,
+    ];
+
+    fn bit(self) -> usize {
+        1 << Extension::ALL.iter().position(|&ext| ext == self).unwrap()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Extension::Time => "TIME",
+            Extension::Ipi => "IPI",
+            Extension::Rfence => "RFENCE",
+            Extension::Hsm => "HSM",
+            Extension::Srst => "SRST",
+            Extension::Dbcn => "DBCN",
+            Extension::Pmu => "PMU",
+            Extension::Susp => "SUSP",
+        }
+    }
+}
+
+/// Whether [`init`] found `ext`
+pub fn has(ext: Extension) -> bool {
+    AVAILABLE.load(Ordering::Relaxed) & ext.bit() != 0
+}
+
+/// Probes every [`Extension`] and prints what the SBI implementation supports
+pub fn init() {
+    let mut available = 0;
+    for ext in Extension::ALL {
+        if sbi_probe_extension(ext as u64).is_ok_and(|found| found != 0) {
+            available |= ext.bit();
+        }
+    }
+    AVAILABLE.store(available, Ordering::Relaxed);
+
+    // Base extension calls can't fail, but there's no need to trust that
+    let spec = sbi_get_spec_version().unwrap_or(0);
+    let (major, minor) = ((spec >> 24) & 0x7f, spec & 0xff_ffff);
+    let impl_id = sbi_get_impl_id().unwrap_or(-1);
+    let impl_version = sbi_get_impl_version().unwrap_or(0);
+
+    match ImplId::from_id(impl_id) {
+        Some(imp) => Sbi::kprint(format_args!(
+            "spec v{major}.{minor}, {imp:?} (impl id {impl_id}) version {impl_version:#x}"
+        )),
+        None => Sbi::kprint(format_args!(
+            "spec v{major}.{minor}, unknown implementation (impl id {impl_id}) version {impl_version:#x}"
+        )),
+    }
+    Sbi::kprint(format_args!("available: {}", ExtensionList(available)));
+    Sbi::kprint(format_args!("missing: {}", ExtensionList(!available)));
+}
+
+/// Names of the extensions in a mask of [`Extension`] bits
+struct ExtensionList(usize);
+
+impl fmt::Display for ExtensionList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut exts = Extension::ALL.into_iter().filter(|ext| self.0 & ext.bit() != 0);
+        let Some(first) = exts.next() else {
+            return f.write_str("none");
+        };
+
+        f.write_str(first.name())?;
+        for ext in exts {
+            write!(f, " {}", ext.name())?;
+        }
+        Ok(())
+    }
 }
 
 #[rustfmt::skip]
@@ -56,7 +228,7 @@ unsafe fn sbi_call(
     arg5: u64,
     fid: u64,
     eid: u64
-) -> SbiRet {
+) -> Result<i64, SbiError> {
     unsafe {
         asm!(
             "ecall",
@@ -71,24 +243,14 @@ unsafe fn sbi_call(
         )
     }
 
-    unsafe {
-        core::mem::transmute(
-            SbiRetInto {
-                error: arg0.cast_signed(),
-                value: arg1.cast_signed(),
-            }
-        )
+    match arg0.cast_signed() {
+        0 => Ok(arg1.cast_signed()),
+        code => Err(SbiError::from_code(code)),
     }
 }
 
-/// Returns the imlementation ID
-#[allow(dead_code)]
-pub fn sbi_get_impl_id() -> crate::sbi::SbiRet {
-    unsafe { sbi_call(0, 0, 0, 0, 0, 0, 0x1, 0x10) }
-}
-
-/// Legacy call (incompatiable with [`sbi_call`])
-pub fn sbi_getchar() -> i64 {
+/// Legacy call (incompatiable with [`sbi_call`]), fails if there's nothing to read
+pub fn sbi_getchar() -> Result<u8, SbiError> {
     let mut arg0: i64 = 0;
     unsafe {
         asm!(
@@ -104,14 +266,14 @@ pub fn sbi_getchar() -> i64 {
         )
     }
 
-    arg0
+    u8::try_from(arg0).map_err(|_| SbiError::Failed)
 }
 
 macro_rules! define_sbi_fn {
     ($name:ident, $fid:literal, $eid:literal, $(,)? $(#[$attr:meta])*) => {
         #[allow(dead_code)]
         $(#[$attr])*
-        pub fn $name() -> $crate::sbi::SbiRet {
+        pub fn $name() -> Result<i64, $crate::sbi::SbiError> {
             unsafe {
                 sbi_call(0, 0, 0, 0, 0, 0, $fid, $eid)
             }
@@ -120,7 +282,7 @@ macro_rules! define_sbi_fn {
     ($name:ident, $fid:literal, $eid:literal, $arg:ident: $ty:ty, $(,)? $(#[$attr:meta])*) => {
         #[allow(dead_code)]
         $(#[$attr])*
-        pub fn $name($arg: $ty) -> $crate::sbi::SbiRet {
+        pub fn $name($arg: $ty) -> Result<i64, $crate::sbi::SbiError> {
             unsafe {
                 sbi_call($arg as u64, 0, 0, 0, 0, 0, $fid, $eid)
             }
@@ -129,7 +291,7 @@ macro_rules! define_sbi_fn {
     ($name:ident, $fid:literal, $eid:literal, $arg0:ident: $ty0:ty, $arg1:ident: $ty1:ty, $(,)?  $(#[$attr:meta])*) => {
         #[allow(dead_code)]
         $(#[$attr])*
-        pub fn $name($arg0: $ty0, $arg1: $ty1) -> $crate::sbi::SbiRet {
+        pub fn $name($arg0: $ty0, $arg1: $ty1) -> Result<i64, $crate::sbi::SbiError> {
             unsafe {
                 sbi_call($arg0 as u64, $arg1 as u64, 0, 0, 0, 0, $fid, $eid)
             }
@@ -138,7 +300,7 @@ macro_rules! define_sbi_fn {
     ($name:ident, $fid:literal, $eid:literal, $arg0:ident: $ty0:ty, $arg1:ident: $ty1:ty, $arg2:ident: $ty2:ty, $(,)? $(#[$attr:meta])*) => {
         #[allow(dead_code)]
         $(#[$attr])*
-        pub fn $name($arg0: $ty0, $arg1: $ty1, $arg2: $ty2) -> $crate::sbi::SbiRet {
+        pub fn $name($arg0: $ty0, $arg1: $ty1, $arg2: $ty2) -> Result<i64, $crate::sbi::SbiError> {
             unsafe {
                 sbi_call($arg0 as u64, $arg1 as u64, $arg2 as u64, 0, 0, 0, $fid, $eid)
             }
@@ -147,12 +309,21 @@ macro_rules! define_sbi_fn {
     ($name:ident, $fid:literal, $eid:literal, $arg0:ident: $ty0:ty, $arg1:ident: $ty1:ty, $arg2:ident: $ty2:ty, $arg3:ident: $ty3:ty, $(,)? $(#[$attr:meta])*) => {
         #[allow(dead_code)]
         $(#[$attr])*
-        pub fn $name($arg0: $ty0, $arg1: $ty1, $arg2: $ty2, $arg3: $ty3) -> $crate::sbi::SbiRet {
+        pub fn $name($arg0: $ty0, $arg1: $ty1, $arg2: $ty2, $arg3: $ty3) -> Result<i64, $crate::sbi::SbiError> {
             unsafe {
                 sbi_call($arg0 as u64, $arg1 as u64, $arg2 as u64, $arg3 as u64, 0, 0, $fid, $eid)
             }
         }
     };
+    ($name:ident, $fid:literal, $eid:literal, $arg0:ident: $ty0:ty, $arg1:ident: $ty1:ty, $arg2:ident: $ty2:ty, $arg3:ident: $ty3:ty, $arg4:ident: $ty4:ty, $(,)? $(#[$attr:meta])*) => {
+        #[allow(dead_code)]
+        $(#[$attr])*
+        pub fn $name($arg0: $ty0, $arg1: $ty1, $arg2: $ty2, $arg3: $ty3, $arg4: $ty4) -> Result<i64, $crate::sbi::SbiError> {
+            unsafe {
+                sbi_call($arg0 as u64, $arg1 as u64, $arg2 as u64, $arg3 as u64, $arg4 as u64, 0, $fid, $eid)
+            }
+        }
+    };
 }
 
 macro_rules! sbi_fns {
@@ -170,6 +341,12 @@ sbi_fns!(
     [sbi_get_spec_version, 0x0, 0x10,
         /// Returns the current SBI version. Must succeed
     ],
+    [sbi_get_impl_id, 0x1, 0x10,
+        /// Returns the implementation ID, see [`ImplId`]
+    ],
+    [sbi_get_impl_version, 0x2, 0x10,
+        /// Returns the implementation specific version
+    ],
     [sbi_probe_extension, 3, 0x10, extension_id: u64,
         /// Returns 0 if EID extension_id is not available,
         /// and 1 if it is, unless defined by impl as some
@@ -184,11 +361,14 @@ sbi_fns!(
     [sbi_get_mimpid, 6, 0x10,
         /// Returns legal value for `mimpid`. 0 is always valid
     ],
+    [sbi_legacy_set_timer, 0x0, 0x0, stime_value: u64,
+        /// Legacy call (compatiable with [`sbi_call`]), for when there's no TIME extension
+    ],
     [sbi_set_timer, 0, 0x54494D45, stime_value: u64,
         /// Sets the `stime` csr
     ],
     [sbi_send_ipi, 0, 0x735049, hart_mask: u64, hart_mask_base: u64,
-        /// Sends an inter-process interrupt to all harts in `hart_mask`.
+        /// Sends an inter-processor interrupt to all harts in `hart_mask`.
         /// These are recieved as software interrupts.
     ],
     [sbi_hart_start, 0, 0x48534D, hartid: u64, start_addr: usize, opaque: u64,
@@ -200,7 +380,7 @@ sbi_fns!(
         /// The [`sbi_hart_stop`] must be called with the supervisor-mode interrupts disabled.
     ],
     [sbi_hart_get_status, 2, 0x48534D, hart_id: u64,
-        /// Gets current [`HartState`] or returns [`SbiRet::SbiErrInvalidParam`]
+        /// Gets current [`HartState`] or fails with [`SbiError::InvalidParam`]
     ],
     [sbi_hart_suspend, 3, 0x48534D, suspend_type: u32, resume_addr: u64, opaque: u64,
         /// Suspends the hart. Returning from a non-retentive suspend, the hart resumes
@@ -209,25 +389,28 @@ sbi_fns!(
     [sbi_system_reset, 0, 0x53525354, reset_type: u32, reset_reason: u32,
         /// Reset the cpu. Does not return on success.
     ],
-    [sbi_remote_fence_i, 0, 0x52464E43,
-        /// TODO
+    [sbi_remote_fence_i, 0, 0x52464E43, hart_mask: u64, hart_mask_base: u64,
+        /// Executes `fence.i` on all harts in `hart_mask`
     ],
-    [sbi_remote_sfence_vma, 1, 0x52464E43,
-        /// TODO
+    [sbi_remote_sfence_vma, 1, 0x52464E43, hart_mask: u64, hart_mask_base: u64, start_addr: usize, size: usize,
+        /// Executes `sfence.vma` for `start_addr..start_addr + size` on all harts in
+        /// `hart_mask`. A `start_addr` and `size` of 0, or a `size` of `usize::MAX`,
+        /// flushes everything. Returns once every hart is done.
     ],
-    [sbi_remote_sfence_vma_asid, 2, 0x52464E43,
-        /// TODO
+    [sbi_remote_sfence_vma_asid, 2, 0x52464E43, hart_mask: u64, hart_mask_base: u64, start_addr: usize, size: usize, asid: usize,
+        /// Like [`sbi_remote_sfence_vma`], only for entries tagged with `asid`
     ],
-    [sbi_remote_hfence_gvma_vmid, 3, 0x52464E43,
-        /// TODO
+    [sbi_remote_hfence_gvma_vmid, 3, 0x52464E43, hart_mask: u64, hart_mask_base: u64, start_addr: usize, size: usize, vmid: usize,
+        /// Executes `hfence.gvma` for guest physical addresses of `vmid` on all harts in
+        /// `hart_mask`. Needs the hypervisor extension.
     ],
-    [sbi_remote_hfence_gvma, 4, 0x52464E43,
-        /// TODO
+    [sbi_remote_hfence_gvma, 4, 0x52464E43, hart_mask: u64, hart_mask_base: u64, start_addr: usize, size: usize,
+        /// Executes `hfence.gvma` for guest physical addresses of every VMID
     ],
-    [sbi_remote_hfence_vvma_asid, 5, 0x52464E43,
-        /// TODO
+    [sbi_remote_hfence_vvma_asid, 5, 0x52464E43, hart_mask: u64, hart_mask_base: u64, start_addr: usize, size: usize, asid: usize,
+        /// Executes `hfence.vvma` for guest virtual addresses of `asid` in the current VMID
     ],
-    [sbi_remote_hfence_vvma, 6, 0x52464E43,
-        /// TODO
+    [sbi_remote_hfence_vvma, 6, 0x52464E43, hart_mask: u64, hart_mask_base: u64, start_addr: usize, size: usize,
+        /// Executes `hfence.vvma` for guest virtual addresses of every ASID in the current VMID
     ],
 );
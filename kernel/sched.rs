@@ -0,0 +1,212 @@
+//! Scheduling policies
+//!
+//! A [`Scheduler`] owns the run queue. The running process is never on it, [`crate::proc::r#yield`]
+//! puts it back (if it's still runnable) before asking for the next one. Which policy is used
+//! is picked with `sched=rr|prio|fair` in the kernel command line, round robin by default.
+
+use ralloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+};
+use utils::sync::Once;
+
+use crate::{
+    dtree::DeviceTree,
+    lock::{IrqSpinLock, IrqSpinLockGuard},
+    proc::Process,
+    traits::KSay,
+};
+
+pub const NICE_MIN: isize = -20;
+pub const NICE_MAX: isize = 19;
+
+static SCHED: Once<IrqSpinLock<Box<dyn Scheduler>>> = Once::new();
+
+pub trait Scheduler: Send {
+    fn name(&self) -> &'static str;
+
+    /// Adds a runnable process to the run queue
+    fn enqueue(&mut self, proc: &mut Process);
+
+    /// Takes the process that should run next off the run queue
+    fn pick_next(&mut self) -> Option<usize>;
+
+    /// Takes a queued process off the run queue
+    fn remove(&mut self, proc: &Process);
+
+    /// Charges a tick to the running process, returns whether it should be preempted
+    fn tick(&mut self, proc: &mut Process) -> bool {
+        proc.slice = proc.slice.saturating_sub(1);
+        proc.slice == 0
+    }
+}
+
+pub struct Sched;
+
+impl KSay for Sched {
+    const NAME: &'static str = "sched";
+}
+
+/// Picks the policy from the kernel command line, must happen before any process is created
+pub fn init(dtree: &DeviceTree) {
+    let name = dtree.bootarg("sched").unwrap_or("rr");
+    let sched: Box<dyn Scheduler> = match name {
+        "rr" => Box::new(RoundRobin::new()),
+        "prio" => Box::new(Priority::new()),
+        "fair" => Box::new(Fair::new()),
+        _ => {
+            Sched::kprint(format_args!("unknown scheduler {name:?}, using round robin"));
+            Box::new(RoundRobin::new())
+        }
+    };
+
+    Sched::kprint(format_args!("using {} scheduler", sched.name()));
+    SCHED.call_once(|| IrqSpinLock::new(sched));
+}
+
+/// Don't hold it across a context switch
+pub fn sched() -> IrqSpinLockGuard<'static, Box<dyn Scheduler>> {
+    SCHED.get().expect("scheduler is uninitalized").lock()
+}
+
+/// Every runnable process gets the same time slice in turn, nice values are ignored
+pub struct RoundRobin {
+    queue: VecDeque<usize>,
+}
+
+impl RoundRobin {
+    pub const fn new() -> Self {
+        RoundRobin { queue: VecDeque::new() }
+    }
+}
+
+impl Scheduler for RoundRobin {
+    fn name(&self) -> &'static str {
+        "round robin"
+    }
+
+    fn enqueue(&mut self, proc: &mut Process) {
+        self.queue.push_back(proc.pid);
+    }
+
+    fn pick_next(&mut self) -> Option<usize> {
+        self.queue.pop_front()
+    }
+
+    fn remove(&mut self, proc: &Process) {
+        self.queue.retain(|&pid| pid != proc.pid);
+    }
+}
+
+/// The lowest nice value always runs first, round robin between equal ones. A process with
+/// a lower nice value than the running one preempts it on the next tick.
+pub struct Priority {
+    queues: BTreeMap<isize, VecDeque<usize>>,
+}
+
+impl Priority {
+    pub const fn new() -> Self {
+        Priority { queues: BTreeMap::new() }
+    }
+}
+
+impl Scheduler for Priority {
+    fn name(&self) -> &'static str {
+        "static priority"
+    }
+
+    fn enqueue(&mut self, proc: &mut Process) {
+        self.queues.entry(proc.nice).or_default().push_back(proc.pid);
+    }
+
+    fn pick_next(&mut self) -> Option<usize> {
+        let mut queue = self.queues.first_entry()?;
+        let pid = queue.get_mut().pop_front();
+        if queue.get().is_empty() {
+            queue.remove();
+        }
+        pid
+    }
+
+    fn remove(&mut self, proc: &Process) {
+        if let Some(queue) = self.queues.get_mut(&proc.nice) {
+            queue.retain(|&pid| pid != proc.pid);
+            if queue.is_empty() {
+                self.queues.remove(&proc.nice);
+            }
+        }
+    }
+
+    fn tick(&mut self, proc: &mut Process) -> bool {
+        proc.slice = proc.slice.saturating_sub(1);
+        let higher_queued = self.queues.first_key_value().is_some_and(|(&nice, _)| nice < proc.nice);
+        proc.slice == 0 || higher_queued
+    }
+}
+
+/// Weight of a process at nice 0, every step of nice is about 10% more or less CPU time
+const NICE_0_WEIGHT: usize = 1024;
+/// Virtual runtime a process at nice 0 is charged per tick
+const VRUNTIME_PER_TICK: usize = 1 << 10;
+
+#[rustfmt::skip]
+const NICE_WEIGHTS: [usize; 40] = [
+    /* -20 */ 88761, 71755, 56483, 46273, 36291,
+    /* -15 */ 29154, 23254, 18705, 14949, 11916,
+    /* -10 */  9548,  7620,  6100,  4904,  3906,
+    /*  -5 */  3121,  2501,  1991,  1586,  1277,
+    /*   0 */  1024,   820,   655,   526,   423,
+    /*   5 */   335,   272,   215,   172,   137,
+    /*  10 */   110,    87,    70,    56,    45,
+    /*  15 */    36,    29,    23,    18,    15,
+];
+
+fn weight(nice: isize) -> usize {
+    NICE_WEIGHTS[(nice.clamp(NICE_MIN, NICE_MAX) - NICE_MIN) as usize]
+}
+
+/// Runs whoever has had the least virtual runtime. Virtual runtime grows slower the lower
+/// the nice value, so CPU time is shared out by weight instead of strictly by priority.
+pub struct Fair {
+    /// Ordered by `(vruntime, pid)`
+    queue: BTreeSet<(usize, usize)>,
+    /// Never goes backwards, new and woken processes start here so they can't hog the hart
+    /// to catch up on time they weren't runnable for
+    min_vruntime: usize,
+}
+
+impl Fair {
+    pub const fn new() -> Self {
+        Fair {
+            queue: BTreeSet::new(),
+            min_vruntime: 0,
+        }
+    }
+}
+
+impl Scheduler for Fair {
+    fn name(&self) -> &'static str {
+        "fair"
+    }
+
+    fn enqueue(&mut self, proc: &mut Process) {
+        proc.vruntime = proc.vruntime.max(self.min_vruntime);
+        self.queue.insert((proc.vruntime, proc.pid));
+    }
+
+    fn pick_next(&mut self) -> Option<usize> {
+        let (vruntime, pid) = self.queue.pop_first()?;
+        self.min_vruntime = self.min_vruntime.max(vruntime);
+        Some(pid)
+    }
+
+    fn remove(&mut self, proc: &Process) {
+        self.queue.remove(&(proc.vruntime, proc.pid));
+    }
+
+    fn tick(&mut self, proc: &mut Process) -> bool {
+        proc.vruntime += VRUNTIME_PER_TICK * NICE_0_WEIGHT / weight(proc.nice);
+        proc.slice = proc.slice.saturating_sub(1);
+        proc.slice == 0
+    }
+}
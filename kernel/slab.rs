@@ -0,0 +1,263 @@
+//! Slab object caches
+//!
+//! A [`SlabCache`] hands out objects of a single size, carved out of slabs of contiguous
+//! frames from [`FRAME_ALLOC`]. Slabs move between the `partial`, `full` and `empty` lists as
+//! objects are allocated and freed. [`Cache`] is the typed wrapper the rest of the kernel uses.
+
+use core::{fmt::Display, marker::PhantomData, ptr::NonNull};
+
+use owo_colors::{OwoColorize, colors::*};
+use ralloc::vec::Vec;
+use utils::sync::TicketLock;
+
+use crate::{frame::{FRAME_ALLOC, FrameError}, paging::PAGE_SIZE, traits::KSay};
+
+/// Slabs grow until they hold at least this many objects (or hit [`MAX_SLAB_PAGES`])
+const MIN_OBJECTS: usize = 8;
+const MAX_SLAB_PAGES: usize = 16;
+/// Empty slabs kept around instead of being returned to the frame allocator
+const MAX_EMPTY: usize = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlabError {
+    OutOfMemory,
+    /// Pointer does not belong to any slab of the cache
+    NotOwned,
+}
+
+impl From<FrameError> for SlabError {
+    fn from(_: FrameError) -> Self {
+        SlabError::OutOfMemory
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub obj_size: usize,
+    pub slab_pages: usize,
+    pub partial: usize,
+    pub full: usize,
+    pub empty: usize,
+    pub in_use: usize,
+    pub capacity: usize,
+    pub allocs: usize,
+    pub frees: usize,
+}
+
+/// Free objects hold a pointer to the next free object
+struct FreeObj {
+    next: Option<NonNull<FreeObj>>,
+}
+
+struct Slab {
+    base: *mut u8,
+    free: Option<NonNull<FreeObj>>,
+    in_use: usize,
+}
+
+// Slabs are only reachable through the cache's lock
+unsafe impl Send for Slab {}
+
+pub struct SlabCache {
+    name: &'static str,
+    obj_size: usize,
+    slab_pages: usize,
+    per_slab: usize,
+    partial: Vec<Slab>,
+    full: Vec<Slab>,
+    empty: Vec<Slab>,
+    allocs: usize,
+    frees: usize,
+}
+
+impl KSay for SlabCache {
+    const NAME: &'static str = "slab";
+}
+
+impl Slab {
+    fn new(obj_size: usize, pages: usize, per_slab: usize) -> Result<Slab, SlabError> {
+        let base = FRAME_ALLOC.alloc_frames(pages)?;
+
+        // Thread the free list back to front so objects are handed out in address order
+        let mut free = None;
+        for i in (0..per_slab).rev() {
+            let obj = unsafe { base.add(i * obj_size) }.cast::<FreeObj>();
+            unsafe {
+                obj.write(FreeObj { next: free });
+                free = Some(NonNull::new_unchecked(obj));
+            }
+        }
+
+        Ok(Slab { base, free, in_use: 0 })
+    }
+
+    fn contains(&self, ptr: *mut u8, pages: usize) -> bool {
+        (self.base..self.base.wrapping_add(pages * PAGE_SIZE)).contains(&ptr)
+    }
+}
+
+impl SlabCache {
+    pub const fn new(name: &'static str, size: usize, align: usize) -> SlabCache {
+        assert!(align <= PAGE_SIZE, "slab objects can't be aligned past a page");
+
+        let align = if align < align_of::<FreeObj>() { align_of::<FreeObj>() } else { align };
+        let size = if size < size_of::<FreeObj>() { size_of::<FreeObj>() } else { size };
+        let obj_size = size.next_multiple_of(align);
+
+        let mut slab_pages = 1;
+        while slab_pages < MAX_SLAB_PAGES && slab_pages * PAGE_SIZE / obj_size < MIN_OBJECTS {
+            slab_pages *= 2;
+        }
+        while slab_pages * PAGE_SIZE < obj_size {
+            slab_pages *= 2;
+        }
+
+        SlabCache {
+            name,
+            obj_size,
+            slab_pages,
+            per_slab: slab_pages * PAGE_SIZE / obj_size,
+            partial: Vec::new(),
+            full: Vec::new(),
+            empty: Vec::new(),
+            allocs: 0,
+            frees: 0,
+        }
+    }
+
+    pub fn alloc(&mut self) -> Result<NonNull<u8>, SlabError> {
+        if self.partial.is_empty() {
+            let slab = match self.empty.pop() {
+                Some(slab) => slab,
+                None => Slab::new(self.obj_size, self.slab_pages, self.per_slab)?,
+            };
+            self.partial.push(slab);
+        }
+
+        let index = self.partial.len() - 1;
+        let slab = &mut self.partial[index];
+        let obj = slab.free.expect("partial slab without free objects");
+        slab.free = unsafe { obj.as_ref().next };
+        slab.in_use += 1;
+
+        if slab.in_use == self.per_slab {
+            let slab = self.partial.swap_remove(index);
+            self.full.push(slab);
+        }
+
+        self.allocs += 1;
+        Ok(obj.cast())
+    }
+
+    /// # Safety
+    /// `ptr` must have come from [`SlabCache::alloc`] on this cache and not be freed yet
+    pub unsafe fn free(&mut self, ptr: NonNull<u8>) -> Result<(), SlabError> {
+        let pages = self.slab_pages;
+        let raw = ptr.as_ptr();
+
+        let mut slab = if let Some(i) = self.partial.iter().position(|s| s.contains(raw, pages)) {
+            self.partial.swap_remove(i)
+        } else if let Some(i) = self.full.iter().position(|s| s.contains(raw, pages)) {
+            self.full.swap_remove(i)
+        } else {
+            return Err(SlabError::NotOwned);
+        };
+
+        let obj = ptr.cast::<FreeObj>();
+        unsafe { obj.write(FreeObj { next: slab.free }) };
+        slab.free = Some(obj);
+        slab.in_use -= 1;
+        self.frees += 1;
+
+        if slab.in_use > 0 {
+            self.partial.push(slab);
+        } else if self.empty.len() < MAX_EMPTY {
+            self.empty.push(slab);
+        } else {
+            FRAME_ALLOC
+                .free_frames(slab.base, pages)
+                .expect("slab frames were already freed");
+        }
+
+        Ok(())
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let slabs = self.partial.len() + self.full.len() + self.empty.len();
+        CacheStats {
+            name: self.name,
+            obj_size: self.obj_size,
+            slab_pages: self.slab_pages,
+            partial: self.partial.len(),
+            full: self.full.len(),
+            empty: self.empty.len(),
+            in_use: self.partial.iter().chain(self.full.iter()).map(|slab| slab.in_use).sum(),
+            capacity: slabs * self.per_slab,
+            allocs: self.allocs,
+            frees: self.frees,
+        }
+    }
+}
+
+/// Typed object cache for `T`
+pub struct Cache<T> {
+    inner: TicketLock<SlabCache>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Cache<T> {
+    pub const fn new(name: &'static str) -> Cache<T> {
+        Cache {
+            inner: TicketLock::new(SlabCache::new(name, size_of::<T>(), align_of::<T>())),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns uninitalized memory for a `T`
+    pub fn alloc(&self) -> Result<*mut T, SlabError> {
+        self.inner.lock().alloc().map(|ptr| ptr.as_ptr().cast())
+    }
+
+    pub fn alloc_zeroed(&self) -> Result<*mut T, SlabError> {
+        let ptr = self.alloc()?;
+        unsafe { core::ptr::write_bytes(ptr, 0, 1) };
+        Ok(ptr)
+    }
+
+    /// Does not drop the `T`
+    ///
+    /// # Safety
+    /// `ptr` must have come from this cache and not be used afterwards
+    pub unsafe fn free(&self, ptr: *mut T) -> Result<(), SlabError> {
+        let ptr = NonNull::new(ptr).ok_or(SlabError::NotOwned)?;
+        unsafe { self.inner.lock().free(ptr.cast()) }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock().stats()
+    }
+
+    pub fn print_stats(&self) {
+        <SlabCache as KSay>::kprint(self.stats());
+    }
+}
+
+impl Display for CacheStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{} ({}B objects, {} pages/slab): {}/{} in use, slabs {}/{}/{} partial/full/empty, {} allocs, {} frees",
+            self.name.fg::<BrightCyan>(),
+            self.obj_size,
+            self.slab_pages,
+            self.in_use,
+            self.capacity,
+            self.partial,
+            self.full,
+            self.empty,
+            self.allocs,
+            self.frees
+        )
+    }
+}
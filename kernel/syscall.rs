@@ -1,29 +1,81 @@
 use core::{slice, str};
 
-use crate::{PROC_CURR, proc::{Process, ProcessState, r#yield}, sbi::{sbi_getchar, sbi_putchar}, trap::TrapFrame};
+use ralloc::vec::Vec;
 
-use utils::syscall::consts::*;
+use crate::{
+    hart::curr_proc,
+    console,
+    elf::ElfError,
+    ext2::FsError,
+    paging::PagingError,
+    power::{self, Power},
+    proc::{ProcessError, exec, exit_current, fork, set_nice, wait},
+    sbi::{ResetReason, sbi_putchar},
+    timer,
+    traits::KSay,
+    trap::TrapFrame,
+    user::{USER_COPY_MAX, UserError, copy_from_user, copy_strings_from_user, copy_to_user},
+};
+
+use utils::{FileErr, syscall::consts::*};
 
 pub fn handle_syscall(f: &mut TrapFrame) {
     match f.a4 {
         SYS_PUTCHAR => {
-            sbi_putchar(f.a0 as u8);
+            // Nothing the caller could do about a lost byte
+            let _ = sbi_putchar(f.a0 as u8);
             f.a0 = 0;
         }
-        SYS_GETCHAR => loop {
-            let char = sbi_getchar();
-            if char >= 0 {
-                f.a0 = 0;
-                f.a1 = char as usize;
-                break;
-            }
-            r#yield();
+        SYS_GETCHAR => {
+            f.a1 = console::getchar() as usize;
+            f.a0 = 0;
+        }
+        SYS_SLEEP => {
+            timer::sleep_ms(f.a0);
+            f.a0 = 0;
         }
         SYS_EXIT => {
-            let curr_proc: &mut Process = unsafe { PROC_CURR.unwrap().as_mut().unwrap() };
-            println!("Process exiting: {}", curr_proc.pid);
-            curr_proc.state = ProcessState::Exited;
-            r#yield();
+            exit_current(f.a0);
+        }
+        SYS_WAIT => {
+            let result = sys_wait(None, f.a0);
+            set_result(f, result);
+        }
+        SYS_WAITPID => {
+            // -1 waits for any child, like wait
+            let pid = Some(f.a0).filter(|&pid| pid as isize != -1);
+            let result = sys_wait(pid, f.a1);
+            set_result(f, result);
+        }
+        SYS_FORK => {
+            let result = fork(f).map_err(FileErr::from);
+            set_result(f, result);
+        }
+        SYS_NICE => {
+            // pid 0 is the caller, the idle process can't be reniced anyway
+            let pid = match f.a0 {
+                0 => unsafe { (*curr_proc().unwrap()).pid },
+                pid => pid,
+            };
+            let result = set_nice(pid, f.a1 as isize).map(|nice| nice as usize).map_err(FileErr::from);
+            set_result(f, result);
+        }
+        SYS_SHUTDOWN => {
+            // Only back if the SBI couldn't do it
+            let err = power::shutdown(ResetReason::NoReason);
+            Power::kprint(format_args!("shutdown failed: {err:?}"));
+            set_result(f, Err(FileErr::NotSupported));
+        }
+        SYS_REBOOT => {
+            let err = power::reboot(ResetReason::NoReason);
+            Power::kprint(format_args!("reboot failed: {err:?}"));
+            set_result(f, Err(FileErr::NotSupported));
+        }
+        SYS_EXEC => {
+            // Returns only on failure
+            if let Err(err) = sys_exec(f) {
+                set_result(f, Err(err));
+            }
         }
         SYS_WRITE => {
             todo!()
@@ -35,3 +87,71 @@ pub fn handle_syscall(f: &mut TrapFrame) {
     }
 }
 
+
+/// Results go back in `a0` (0 or -1) and `a1` (the value or a [`FileErr`])
+fn set_result(f: &mut TrapFrame, result: Result<usize, FileErr>) {
+    match result {
+        Ok(value) => {
+            f.a0 = 0;
+            f.a1 = value;
+        }
+        Err(err) => {
+            f.a0 = -1isize as usize;
+            f.a1 = err as usize;
+        }
+    }
+}
+
+/// `exec(path, path_len, argv, envp)`
+fn sys_exec(f: &mut TrapFrame) -> Result<(), FileErr> {
+    let vmas = unsafe { &(*curr_proc().unwrap()).vmas };
+
+    if f.a1 > USER_COPY_MAX {
+        return Err(FileErr::InvalidArgument);
+    }
+    let path: Vec<u8> = copy_from_user(vmas, f.a0, f.a1)?;
+    let argv = copy_strings_from_user(vmas, f.a2)?;
+    let envp = copy_strings_from_user(vmas, f.a3)?;
+
+    exec(f, &path, &argv, &envp)?;
+    Ok(())
+}
+
+/// `wait(status)` and `waitpid(pid, status)`, `status_ptr` may be null
+fn sys_wait(pid: Option<usize>, status_ptr: usize) -> Result<usize, FileErr> {
+    let (pid, status) = wait(pid)?;
+
+    if status_ptr != 0 {
+        let vmas = unsafe { &(*curr_proc().unwrap()).vmas };
+        copy_to_user(vmas, status_ptr, &status.to_le_bytes())?;
+    }
+
+    Ok(pid)
+}
+
+impl From<UserError> for FileErr {
+    fn from(err: UserError) -> Self {
+        match err {
+            UserError::BadAddress => FileErr::BadAddress,
+            UserError::TooLong => FileErr::BufferTooLarge,
+        }
+    }
+}
+
+impl From<ProcessError> for FileErr {
+    fn from(err: ProcessError) -> Self {
+        match err {
+            ProcessError::OutOfMemory
+            | ProcessError::MaxProcsUsed
+            | ProcessError::Paging(PagingError::OutOfMemory)
+            | ProcessError::Elf(ElfError::Paging(PagingError::OutOfMemory)) => FileErr::OutOfMemory,
+            ProcessError::Fs(FsError::NotAFile) | ProcessError::Elf(_) | ProcessError::Vma(_) => FileErr::NotExecutable,
+            ProcessError::Fs(FsError::TooLarge) => FileErr::TooLarge,
+            ProcessError::Fs(_) => FileErr::FileNotFound,
+            ProcessError::ArgsTooLong => FileErr::BufferTooLarge,
+            ProcessError::NoChild => FileErr::NoChild,
+            ProcessError::NoSuchProcess => FileErr::NoSuchProcess,
+            ProcessError::Paging(_) => FileErr::BadAddress,
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Timer ticks and time slices
+//!
+//! The timer fires [`TICK_HZ`] times a second and every tick is charged to the running process.
+//! Once a process has used up its quantum it's marked for preemption, the switch itself only
+//! happens on the way back to user mode (see [`crate::trap`]), never in the middle of kernel
+//! code. The quantum can be set with `quantum=<ms>` in the kernel command line.
+//!
+//! Every hart has its own timer, but only the boot hart's ticks count towards [`ticks`].
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{dtree::DeviceTree, hart::hart_id, proc, sbi::{self, Extension, sbi_legacy_set_timer, sbi_set_timer}, traits::KSay, wait::WaitQueue};
+
+pub const TICK_HZ: usize = 100;
+const MS_PER_TICK: usize = 1000 / TICK_HZ;
+/// Used when `/cpus` has no `timebase-frequency`, it's what QEMU's virt machine runs at
+const DEFAULT_TIMEBASE: usize = 10_000_000;
+const DEFAULT_QUANTUM_MS: usize = 50;
+
+/// `time` increments between two ticks
+static TICK_INTERVAL: AtomicUsize = AtomicUsize::new(DEFAULT_TIMEBASE / TICK_HZ);
+/// Ticks a process may run before it's preempted
+static QUANTUM: AtomicUsize = AtomicUsize::new(ms_to_ticks(DEFAULT_QUANTUM_MS));
+/// Ticks since boot
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+/// Hart whose ticks are counted
+static TIMEKEEPER: AtomicUsize = AtomicUsize::new(0);
+
+/// Sleeping processes, all woken at the earliest deadline so each can check its own
+static SLEEP_WAIT: WaitQueue = WaitQueue::new();
+/// Earliest tick a sleeping process wants to wake up at
+static NEXT_WAKEUP: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+pub struct Timer;
+
+impl KSay for Timer {
+    const NAME: &'static str = "timer";
+}
+
+const fn ms_to_ticks(ms: usize) -> usize {
+    let ticks = ms * TICK_HZ / 1000;
+    if ticks == 0 { 1 } else { ticks }
+}
+
+/// Reads the timebase and the quantum from the devicetree and arms the boot hart's first tick
+pub fn init(dtree: &DeviceTree) {
+    TIMEKEEPER.store(hart_id(), Ordering::Relaxed);
+
+    let timebase = dtree
+        .search("/cpus")
+        .and_then(|node| node.find_prop("timebase-frequency"))
+        .and_then(|prop| prop.value().as_array().map(|value| u32::from_be_bytes(*value) as usize))
+        .unwrap_or(DEFAULT_TIMEBASE);
+    TICK_INTERVAL.store(timebase / TICK_HZ, Ordering::Relaxed);
+
+    if let Some(arg) = dtree.bootarg("quantum") {
+        match arg.parse::<usize>() {
+            Ok(ms) if ms > 0 => set_quantum_ms(ms),
+            _ => Timer::kprint(format_args!("ignoring bad quantum {arg:?}")),
+        }
+    }
+
+    Timer::kprint(format_args!(
+        "{timebase} Hz timebase, {TICK_HZ} Hz tick, {} tick quantum",
+        quantum()
+    ));
+
+    arm();
+}
+
+/// Arms the first tick of a secondary hart, [`init`] must have run already
+pub fn init_hart() {
+    arm();
+}
+
+fn arm() {
+    let deadline = (read_time!() + TICK_INTERVAL.load(Ordering::Relaxed)) as u64;
+    let armed = match sbi::has(Extension::Time) {
+        true => sbi_set_timer(deadline),
+        false => sbi_legacy_set_timer(deadline),
+    };
+
+    if let Err(err) = armed {
+        Timer::kprint(format_args!("failed to arm the timer on hart {}: {err:?}", hart_id()));
+    }
+}
+
+pub fn quantum() -> usize {
+    QUANTUM.load(Ordering::Relaxed)
+}
+
+/// Rounded down to whole ticks, but never below one
+pub fn set_quantum_ms(ms: usize) {
+    QUANTUM.store(ms_to_ticks(ms), Ordering::Relaxed);
+}
+
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Blocks the current process for at least `ms` milliseconds
+pub fn sleep_ms(ms: usize) {
+    let deadline = ticks() + ms.div_ceil(MS_PER_TICK);
+
+    SLEEP_WAIT.wait_until(|| {
+        if ticks() >= deadline {
+            return Some(());
+        }
+        NEXT_WAKEUP.fetch_min(deadline, Ordering::Relaxed);
+        None
+    });
+}
+
+/// Timer interrupt, called with interrupts off from either mode
+pub fn handle_tick() {
+    arm();
+
+    if hart_id() == TIMEKEEPER.load(Ordering::Relaxed) {
+        let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+        if now >= NEXT_WAKEUP.load(Ordering::Relaxed) {
+            NEXT_WAKEUP.store(usize::MAX, Ordering::Relaxed);
+            SLEEP_WAIT.wake_all();
+        }
+    }
+
+    proc::tick();
+}
@@ -0,0 +1,32 @@
+//! TLB shootdowns
+//!
+//! `sfence.vma` only reaches the hart executing it. When a mapping other harts may have
+//! cached changes, every online hart has to drop it before the memory behind it is reused.
+//! The remote harts are fenced by the SBI, which returns once all of them are done, so this
+//! works even though the kernel runs with interrupts off.
+//!
+//! Only mappings shared across harts need this, like the kernel stacks. A process's own
+//! mappings are flushed lazily when it moves to another hart, see [`crate::asid`].
+
+use crate::{
+    hart,
+    paging::{PAGE_SIZE, flush_page},
+    sbi::sbi_remote_sfence_vma,
+};
+
+/// Flushes `start..start + size` on every online hart
+pub fn shootdown_range(start: usize, size: usize) {
+    for page in (start..start + size).step_by(PAGE_SIZE) {
+        flush_page(page);
+    }
+
+    let others = hart::others();
+    if others == 0 {
+        return;
+    }
+
+    // Another hart could keep using the old mapping, nothing is safe after that
+    if let Err(err) = sbi_remote_sfence_vma(others as u64, 0, start, size) {
+        panic!("remote sfence.vma of {start:#x}..{:#x} failed: {err:?}", start + size);
+    }
+}
@@ -1,11 +1,16 @@
 use core::arch::naked_asm;
 
 use crate::{
+    fault,
     interrupt,
+    proc,
     syscall,
 };
 
 pub const SCAUSE_ECALL: usize = 8;
+pub const SCAUSE_INST_PAGE_FAULT: usize = 12;
+pub const SCAUSE_LOAD_PAGE_FAULT: usize = 13;
+pub const SCAUSE_STORE_PAGE_FAULT: usize = 15;
 pub const SCAUSE_INT: usize = 1 << 63;
 
 #[macro_export]
@@ -42,13 +47,29 @@ macro_rules! switch_page_table {
     }};
 }
 
+/// Words in a [`TrapFrame`], an even number keeps `sp` 16 byte aligned
+pub const TRAP_FRAME_WORDS: usize = 34;
+/// Previous privilege was supervisor
+pub const SSTATUS_SPP: usize = 1 << 8;
+
+/// `sscratch` holds the top of the current process's kernel stack while in user mode, and 0
+/// while in the kernel. That's how a trap taken in the kernel (a page fault on a user pointer,
+/// an interrupt) knows to stay on the stack it's already on.
+///
+/// The kernel keeps a pointer to the hart's block in `tp` (see [`crate::hart`]). User mode owns `tp` though, so
+/// [`trap_return`] leaves the hart's value in the last word of the frame, right below the top
+/// of the kernel stack where the next trap from user mode will put its frame again.
 #[unsafe(link_section = ".text.stvec")]
 #[unsafe(naked)]
 #[rustfmt::skip]
 pub extern "C" fn trap_entry() {
     naked_asm!(
         "csrrw sp, sscratch, sp",
-        "addi sp, sp, -8 * 31",
+        "bnez sp, 1f",
+        // Trapped from the kernel, sscratch still holds the kernel sp
+        "csrr sp, sscratch",
+        "1:",
+        "addi sp, sp, -8 * {words}",
         "sd ra,  8 * 0(sp)",
         "sd gp,  8 * 1(sp)",
         "sd tp,  8 * 2(sp)",
@@ -82,13 +103,52 @@ pub extern "C" fn trap_entry() {
 
         "csrr a0, sscratch",
         "sd a0, 8 * 30(sp)",
+        "csrw sscratch, zero",
 
-        "addi a0, sp, 8 * 31",
-        "csrw sscratch, a0",
+        "csrr a0, sepc",
+        "sd a0, 8 * 31(sp)",
+        "csrr a0, sstatus",
+        "sd a0, 8 * 32(sp)",
+
+        // User mode may have put anything in tp, get this hart's block back
+        "andi a0, a0, {spp}",
+        "bnez a0, 2f",
+        "ld tp, 8 * 33(sp)",
+        "2:",
 
         "mv a0, sp",
         "call trap_handler",
 
+        "j {ret}",
+        words = const TRAP_FRAME_WORDS,
+        spp = const SSTATUS_SPP,
+        ret = sym trap_return,
+    )
+}
+
+/// Returns from the trap frame `sp` points at. Also where forked processes first run.
+#[unsafe(naked)]
+#[rustfmt::skip]
+pub extern "C" fn trap_return() {
+    naked_asm!(
+        "ld a0, 8 * 31(sp)",
+        "csrw sepc, a0",
+        "ld a0, 8 * 32(sp)",
+        "csrw sstatus, a0",
+
+        "andi a0, a0, {spp}",
+        "beqz a0, 1f",
+        // Back to the kernel, the process may have moved to another hart since the trap
+        "sd tp, 8 * 2(sp)",
+        "j 2f",
+        "1:",
+        // Going back to user mode, the next trap starts at the top of this kernel stack and
+        // finds this hart's tp where the frame ends
+        "addi a0, sp, 8 * {words}",
+        "csrw sscratch, a0",
+        "sd tp, 8 * 33(sp)",
+        "2:",
+
         "ld ra,  8 * 0(sp)",
         "ld gp,  8 * 1(sp)",
         "ld tp,  8 * 2(sp)",
@@ -121,27 +181,42 @@ pub extern "C" fn trap_entry() {
         "ld s11, 8 * 29(sp)",
         "ld sp,  8 * 30(sp)",
         "sret",
+        words = const TRAP_FRAME_WORDS,
+        spp = const SSTATUS_SPP,
     )
 }
 
 #[unsafe(no_mangle)]
 fn trap_handler(f: &mut TrapFrame) {
     let scause = read_csr!("scause");
-    let sepc = read_csr!("sepc");
+    let sepc = f.sepc;
     let stval = read_csr!("stval");
 
     if scause == SCAUSE_ECALL {
+        // Return past the ecall, syscalls like exec may still move it
+        f.sepc = sepc + 4;
         syscall::handle_syscall(f);
-        write_csr!("sepc", sepc + 4);
-        return;
+    } else if (scause & SCAUSE_INT) != 0 {
+        interrupt::handle_interrupt(scause, sepc, stval);
+    } else if let SCAUSE_INST_PAGE_FAULT | SCAUSE_LOAD_PAGE_FAULT | SCAUSE_STORE_PAGE_FAULT = scause {
+        fault::handle_page_fault(f, scause, stval);
+    } else {
+        panic!(
+            "trap handler: {} at {:#x} (stval={:#x})",
+            scause_name(scause), sepc, stval
+        );
     }
 
-    if (scause & SCAUSE_INT) != 0 {
-        interrupt::handle_interrupt(scause, sepc, stval);
-        return;
+    // Kernel code holds locks and half updated state all over the place, so it's never
+    // switched away from. A tick that lands in the kernel only marks the process, it gets
+    // preempted here once it's back on the way to user mode.
+    if f.from_user() {
+        proc::preempt();
     }
+}
 
-    let scause_readable = match scause {
+pub fn scause_name(scause: usize) -> &'static str {
+    match scause {
         0 => "instruction address misaligned",
         1 => "instruction access fault",
         2 => "illegal instruction",
@@ -154,20 +229,15 @@ fn trap_handler(f: &mut TrapFrame) {
         9 => "environment call from HS-mode",
         10 => "environment call from VS-mode",
         11 => "environment call from M-mode",
-        12 => "instruction page fault",
-        13 => "load page fault",
-        15 => "store/AMO page fault",
+        SCAUSE_INST_PAGE_FAULT => "instruction page fault",
+        SCAUSE_LOAD_PAGE_FAULT => "load page fault",
+        SCAUSE_STORE_PAGE_FAULT => "store/AMO page fault",
         20 => "instruction guest-page fault",
         21 => "load guest-page fault",
         22 => "virtual instruction",
         23 => "store/AMO guest-page fault",
         _ => panic!("unknown scause: {:#x}", scause),
-    };
-
-    panic!(
-        "trap handler: {} at {:#x} (stval={:#x})",
-        scause_readable, sepc, stval
-    );
+    }
 }
 
 #[repr(C, packed)]
@@ -204,4 +274,14 @@ pub struct TrapFrame {
     pub s10: usize,
     pub s11: usize,
     pub sp: usize,
+    pub sepc: usize,
+    pub sstatus: usize,
+    /// What the kernel keeps in `tp`, only valid while the process is in user mode
+    pub kernel_tp: usize,
+}
+
+impl TrapFrame {
+    pub fn from_user(&self) -> bool {
+        self.sstatus & SSTATUS_SPP == 0
+    }
 }
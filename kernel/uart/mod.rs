@@ -1,7 +1,9 @@
 pub mod uart8250;
 pub mod uart16550;
 
-use spin::{Once, mutex::SpinMutex};
+use utils::sync::Once;
+
+use crate::lock::IrqSpinLock;
 pub use uart8250::{Uart as Uart8250};
 pub use uart16550::{Uart as Uart16550};
 
@@ -9,7 +11,7 @@ pub use uart16550::{Uart as Uart16550};
 pub struct UartInitError;
 
 // TODO: Remove once allocated drivers are working.
-pub static UART8250: Once<SpinMutex<Uart8250>> = Once::new();
-pub static UART16550: Once<SpinMutex<Uart16550>> = Once::new();
+pub static UART8250: Once<IrqSpinLock<Uart8250>> = Once::new();
+pub static UART16550: Once<IrqSpinLock<Uart16550>> = Once::new();
 
 
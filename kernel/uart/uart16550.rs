@@ -1,9 +1,9 @@
 use core::fmt::{Debug, Write};
 
 use owo_colors::{OwoColorize, colors::*};
-use spin::mutex::SpinMutex;
 
 use crate::{
+    lock::IrqSpinLock,
     print::{Printer, set_printer},
     registers::*,
     traits::KSay,
@@ -22,6 +22,11 @@ use crate::{
 
 pub struct Uart(&'static mut Registers);
 
+/// Received data available interrupt
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+/// A received byte is waiting in RBR
+const LSR_DATA_READY: u8 = 1 << 0;
+
 impl Printer for Uart {
     fn name(&self) -> &str { "uart16650" }
 }
@@ -41,16 +46,26 @@ unsafe impl Send for Uart {}
 unsafe impl Sync for Uart {}
 
 impl Uart {
-    fn from_ptr(ptr: *mut u8) -> Result<SpinMutex<Uart>, UartInitError> {
+    fn from_ptr(ptr: *mut u8) -> Result<IrqSpinLock<Uart>, UartInitError> {
         if ptr.is_null() {
             Err(UartInitError)
         } else {
             unsafe {
-                Result::Ok(SpinMutex::new(Uart(ptr.cast::<Registers>().as_mut_unchecked())))
+                Result::Ok(IrqSpinLock::new(Uart(ptr.cast::<Registers>().as_mut_unchecked())))
             }
         }
     }
 
+    /// Next received byte, if there is one
+    pub fn read_byte(&mut self) -> Option<u8> {
+        (self.0.lsr.read() & LSR_DATA_READY != 0).then(|| self.0.rbr_thr.read())
+    }
+
+    /// Interrupt whenever a byte arrives
+    pub fn enable_rx_interrupt(&mut self) {
+        self.0.ier.write(IER_RX_AVAILABLE);
+    }
+
     pub fn set_printer(&self) {
         // # Safety:
         // Uh well self has to be the single static UART driver
@@ -77,7 +92,7 @@ impl Write for Uart {
 }
 
 #[inline(never)]
-pub fn init_uart_16650(addr: *mut u8) -> Result<&'static SpinMutex<Uart>, UartInitError> {
+pub fn init_uart_16650(addr: *mut u8) -> Result<&'static IrqSpinLock<Uart>, UartInitError> {
     let uart = UART16550.try_call_once(|| Uart::from_ptr(addr));
 
     match uart {
@@ -2,9 +2,9 @@
 use core::fmt::{Debug, Write};
 
 use owo_colors::{OwoColorize, colors::*};
-use spin::mutex::SpinMutex;
 
 use crate::{
+    lock::IrqSpinLock,
     print::{Printer, set_printer},
     registers::*,
     traits::KSay,
@@ -68,12 +68,12 @@ unsafe impl Send for Uart {}
 unsafe impl Sync for Uart {}
 
 impl Uart {
-    fn from_ptr(ptr: *mut u8) -> Result<SpinMutex<Uart>, UartInitError> {
+    fn from_ptr(ptr: *mut u8) -> Result<IrqSpinLock<Uart>, UartInitError> {
         if ptr.is_null() {
             Err(UartInitError)
         } else {
             unsafe {
-                Result::Ok(SpinMutex::new(Uart(ptr.cast::<Registers>().as_mut_unchecked())))
+                Result::Ok(IrqSpinLock::new(Uart(ptr.cast::<Registers>().as_mut_unchecked())))
             }
         }
     }
@@ -104,7 +104,7 @@ impl Write for Uart {
 }
 
 #[inline(never)]
-pub fn init_uart_8250(addr: *mut u8) -> Result<&'static SpinMutex<Uart>, UartInitError> {
+pub fn init_uart_8250(addr: *mut u8) -> Result<&'static IrqSpinLock<Uart>, UartInitError> {
     let uart = UART8250.try_call_once(|| Uart::from_ptr(addr));
 
     match uart {
@@ -1,19 +1,215 @@
 use core::arch::asm;
 
-use crate::interrupt::SSTATUS_SIE;
+use ralloc::{vec, vec::Vec};
+
+use crate::{
+    elf::Elf,
+    hart::{Hart, curr_proc, this_hart},
+    paging::{GIGAPAGE_SIZE, PAGE_R, PAGE_SIZE, PAGE_W, PageTable, PagingError},
+    proc::finish_switch,
+    trap::{TRAP_FRAME_WORDS, TrapFrame},
+    vma::{Vma, VmaList},
+};
 
 pub const USER_BASE: usize = 0x1000000;
+/// User space is everything below the first root entry, see [`crate::paging::USER_ROOT_INDEX`]
+pub const USER_END: usize = GIGAPAGE_SIZE;
+
+/// Initial size of the heap area
+pub const USER_HEAP_SIZE: usize = 0x100000;
+
+pub const USER_STACK_TOP: usize = USER_END;
+/// Initial size of the stack area
+pub const USER_STACK_SIZE: usize = 0x10000;
+/// The stack grows on faults up to this size. The page below that, and the page above
+/// whatever area is under the stack, are never mapped so overflows fault instead of
+/// running into other memory.
+pub const USER_STACK_MAX: usize = 0x100000;
+
+// Auxiliary vector keys
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+const AT_RANDOM: usize = 25;
+
+/// Longest string copied in from user space, and most entries in a string array
+pub const USER_COPY_MAX: usize = 4096;
 
 pub const SSTATUS_SPIE: usize = 1 << 5;
 pub const SSTATUS_SUM: usize = 1 << 18;
 
 unsafe extern "C" {
-    pub static mut _binary__shell_bin_start: u8;
-    pub static mut _binary__shell_bin_end: u8;
+    pub static mut _binary__shell_elf_start: u8;
+    pub static mut _binary__shell_elf_end: u8;
 }
 
 pub fn userspace_entry() {
-    write_csr!("sepc", USER_BASE);
-    write_csr!("sstatus", SSTATUS_SPIE | SSTATUS_SIE | SSTATUS_SUM);
-    unsafe { asm!("sret") }
+    finish_switch();
+
+    let proc = unsafe { &*curr_proc().unwrap() };
+    // Traps from user mode land on top of this process's kernel stack, and find this hart's
+    // tp there (see crate::trap::trap_entry)
+    write_csr!("sscratch", proc.kstack_top());
+    let frame = (proc.kstack_top() - TRAP_FRAME_WORDS * 8) as *mut TrapFrame;
+    unsafe { (&raw mut (*frame).kernel_tp).write_unaligned(this_hart() as *mut Hart as usize) };
+    write_csr!("sepc", proc.entry);
+    // Interrupts stay off until the sret, sscratch already points at the kernel stack and a
+    // trap taken before then would treat the kernel as user mode
+    write_csr!("sstatus", SSTATUS_SPIE | SSTATUS_SUM);
+    unsafe { asm!("mv sp, {}", "mv tp, zero", "sret", in(reg) proc.user_sp, options(noreturn)) }
+}
+
+/// Not cryptographically anything, just different every time
+fn random_bytes() -> [u8; 16] {
+    let mut state = (read_time!() as u64) ^ ((read_cycle!() as u64) << 32) | 1;
+    let mut bytes = [0; 16];
+    for chunk in bytes.chunks_mut(8) {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        chunk.copy_from_slice(&state.to_le_bytes());
+    }
+    bytes
+}
+
+/// Builds the System V initial stack of a new image at the top of `stack` and returns the
+/// stack pointer. From the top down: a copy of the program headers, the `AT_RANDOM` bytes and
+/// the strings, then, 16 byte aligned at `sp`, `argc`, `argv`, `envp` and the auxiliary vector.
+pub fn init_stack(
+    table: &mut PageTable,
+    stack: &Vma,
+    elf: &Elf,
+    phdrs: &[u8],
+    argv: &[&[u8]],
+    envp: &[&[u8]],
+) -> Result<usize, PagingError> {
+    let top = stack.end;
+    let strings: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+
+    let phdr_addr = (top - strings - 16 - phdrs.len()) & !(size_of::<usize>() - 1);
+    let random_addr = phdr_addr + phdrs.len();
+
+    let mut words = vec![argv.len()];
+    let mut strings = Vec::new();
+    let mut string_addr = random_addr + 16;
+    for list in [argv, envp] {
+        for string in list {
+            words.push(string_addr);
+            strings.push((string_addr, *string));
+            string_addr += string.len() + 1;
+        }
+        words.push(0);
+    }
+
+    for (key, value) in [
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_ENTRY, elf.entry()),
+        (AT_PHDR, phdr_addr),
+        (AT_PHENT, elf.phent()),
+        (AT_PHNUM, elf.phnum()),
+        (AT_RANDOM, random_addr),
+        (AT_NULL, 0),
+    ] {
+        words.extend([key, value]);
+    }
+
+    let sp = (phdr_addr - words.len() * size_of::<usize>()) & !0xf;
+    let mut image = vec![0u8; top - sp];
+    let mut put = |addr: usize, bytes: &[u8]| {
+        image[addr - sp..addr - sp + bytes.len()].copy_from_slice(bytes);
+    };
+
+    put(phdr_addr, phdrs);
+    put(random_addr, &random_bytes());
+    for (addr, string) in strings {
+        put(addr, string);
+    }
+    for (index, word) in words.iter().enumerate() {
+        put(sp + index * size_of::<usize>(), &word.to_le_bytes());
+    }
+
+    stack.populate(table, sp - stack.start, &image)?;
+    Ok(sp)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserError {
+    /// Pointer isn't inside a readable area of the process
+    BadAddress,
+    /// String or array has no terminator within [`USER_COPY_MAX`]
+    TooLong,
+}
+
+/// Checks that `[addr, addr + len)` only touches areas with all of `flags`. The kernel runs
+/// with SUM set, so once this passes the memory can be accessed directly and faults (including
+/// copy-on-write) are resolved like any other.
+fn check_access(vmas: &VmaList, addr: usize, len: usize, flags: usize) -> Result<(), UserError> {
+    let end = addr.checked_add(len).ok_or(UserError::BadAddress)?;
+    let mut curr = addr;
+
+    while curr < end {
+        let vma = vmas.find(curr).ok_or(UserError::BadAddress)?;
+        if vma.flags & flags != flags {
+            return Err(UserError::BadAddress);
+        }
+        curr = vma.end;
+    }
+
+    Ok(())
+}
+
+fn check_readable(vmas: &VmaList, addr: usize, len: usize) -> Result<(), UserError> {
+    check_access(vmas, addr, len, PAGE_R)
+}
+
+/// Copies `data` out to user space
+pub fn copy_to_user(vmas: &VmaList, addr: usize, data: &[u8]) -> Result<(), UserError> {
+    check_access(vmas, addr, data.len(), PAGE_W)?;
+    unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, data.len()) }.copy_from_slice(data);
+    Ok(())
+}
+
+/// Copies `len` bytes in from user space
+pub fn copy_from_user(vmas: &VmaList, addr: usize, len: usize) -> Result<Vec<u8>, UserError> {
+    check_readable(vmas, addr, len)?;
+    Ok(unsafe { core::slice::from_raw_parts(addr as *const u8, len) }.to_vec())
+}
+
+/// Copies in a NUL terminated string, without the NUL
+pub fn copy_cstr_from_user(vmas: &VmaList, addr: usize) -> Result<Vec<u8>, UserError> {
+    let mut string = Vec::new();
+
+    for offset in 0..USER_COPY_MAX {
+        check_readable(vmas, addr + offset, 1)?;
+        match unsafe { (addr as *const u8).add(offset).read() } {
+            0 => return Ok(string),
+            c => string.push(c),
+        }
+    }
+
+    Err(UserError::TooLong)
+}
+
+/// Copies in a null terminated array of pointers to NUL terminated strings, like `argv`.
+/// A null array is empty.
+pub fn copy_strings_from_user(vmas: &VmaList, addr: usize) -> Result<Vec<Vec<u8>>, UserError> {
+    let mut strings = Vec::new();
+    if addr == 0 {
+        return Ok(strings);
+    }
+
+    for index in 0..USER_COPY_MAX {
+        let slot = addr + index * size_of::<usize>();
+        check_readable(vmas, slot, size_of::<usize>())?;
+        match unsafe { (slot as *const usize).read_unaligned() } {
+            0 => return Ok(strings),
+            ptr => strings.push(copy_cstr_from_user(vmas, ptr)?),
+        }
+    }
+
+    Err(UserError::TooLong)
 }
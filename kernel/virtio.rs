@@ -1,10 +1,11 @@
 use core::{
-    alloc::{GlobalAlloc, Layout}, sync::atomic::Ordering, mem::offset_of
+    alloc::{GlobalAlloc, Layout}, sync::atomic::{AtomicBool, AtomicUsize, Ordering}, mem::offset_of
 };
 
 use owo_colors::{OwoColorize, colors::Green};
+use utils::sync::Once;
 
-use crate::{alloc::GLOBAL_ALLOC, traits::KSay};
+use crate::{alloc::GLOBAL_ALLOC, dtree::DeviceTree, frame::FRAME_ALLOC, paging::PAGE_SIZE, plic, proc, traits::KSay, wait::{SleepMutex, WaitQueue}};
 
 use crate::registers::*;
 
@@ -57,9 +58,11 @@ pub struct VirtioDevice {
 impl VirtioDevice {
     fn init_queue(&mut self, index: u32) -> *mut VirtioVirtualQueue {
         unsafe {
-            // TODO: This *gotta* go and be fixed
-            let vq: *mut VirtioVirtualQueue = GLOBAL_ALLOC
-                .alloc(Layout::new::<VirtioVirtualQueue>())
+            // The device wants the whole ring physically contiguous
+            let pages = size_of::<VirtioVirtualQueue>().div_ceil(PAGE_SIZE);
+            let vq: *mut VirtioVirtualQueue = FRAME_ALLOC
+                .alloc_frames(pages)
+                .expect("virtio: out of frames for virtqueue")
                 .cast::<VirtioVirtualQueue>();
             (*vq).queue_index = index;
             (*vq).used_index = &raw mut (*vq).used.index;
@@ -91,17 +94,101 @@ const VIRTIO_BLK_T_OUT: u32 = 1;
 
 // All of these MUST have no padding (using a 64 bit ISA)
 
-// static mut VIRTIO_DEVICE: *mut VirtioDevice = 0x10001000 as *mut VirtioDevice;
-static mut VIRTIO_DEVICE: *mut VirtioDevice = VIRTIO_BLK_PADDR as *mut VirtioDevice;
+/// Where [`VIRTIO_BLK_PADDR`] is mapped, set by [`init_virtio`]
+static mut VIRTIO_DEVICE: *mut VirtioDevice = core::ptr::null_mut();
 
-// These must be initalized by the initalizer for `BLK_REQUEST_VQ`
-static mut BLK_REQUEST_VQ: *mut VirtioVirtualQueue = core::ptr::null_mut();
-static mut BLK_REQ: *mut VirtioBlockReq = core::ptr::null_mut();
-static mut BLK_REQ_PADDR: *mut u8 = core::ptr::null_mut();
-static mut BLK_CAPACITY: usize = 0;
+/// Set up once by [`init_virtio`]. There's only one request buffer, so only one request can
+/// be in flight, and whoever has one in flight holds the lock until it completes.
+static DISK: Once<SleepMutex<Disk>> = Once::new();
 
-pub fn init_virtio() {
+/// Woken when a request completes
+static COMPLETION_WAIT: WaitQueue = WaitQueue::new();
+/// Whether completions interrupt, otherwise requests are polled
+static IRQ_DRIVEN: AtomicBool = AtomicBool::new(false);
+
+struct Disk {
+    vq: *mut VirtioVirtualQueue,
+    /// The one request buffer
+    req: *mut VirtioBlockReq,
+    req_paddr: *mut u8,
+    capacity: usize,
+}
+
+// What the pointers point to is only touched with the lock held
+unsafe impl Send for Disk {}
+
+fn disk() -> &'static SleepMutex<Disk> {
+    DISK.get().expect("virtio-blk is uninitalized")
+}
+
+/// Waits for the request in flight to complete
+fn wait_for_completion(vq: *mut VirtioVirtualQueue) {
+    if IRQ_DRIVEN.load(Ordering::Relaxed) {
+        COMPLETION_WAIT.wait_until(|| (!vq.is_busy()).then_some(()));
+    } else {
+        while vq.is_busy() {}
+    }
+}
+
+/// Sectors each thread of [`spawn_contention_check`] reads
+const CHECK_READS: usize = 64;
+/// Threads of [`spawn_contention_check`] still reading
+static CHECK_RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+/// With `diskcheck` on the kernel command line, starts two kernel threads reading the disk
+/// at the same time, so each keeps waiting for the other's request. A lost wakeup leaves one
+/// of them blocked for good and the check never reports back.
+pub fn spawn_contention_check(dtree: &DeviceTree) {
+    if dtree.bootarg("diskcheck").is_none() {
+        return;
+    }
+
+    CHECK_RUNNING.store(2, Ordering::Relaxed);
+    for sector in 0..2 {
+        if let Err(err) = proc::spawn_kernel_thread(contention_check, sector) {
+            <VirtioDevice as KSay>::kprint(format_args!("no thread for the disk check: {err:?}"));
+        }
+    }
+}
+
+fn contention_check(sector: usize) {
+    let mut buf = [0u8; SECTOR_SIZE];
+    for _ in 0..CHECK_READS {
+        read_disk(&mut buf, sector);
+    }
+
+    if CHECK_RUNNING.fetch_sub(1, Ordering::AcqRel) == 1 {
+        <VirtioDevice as KSay>::kprint(format_args!("contention check passed, {} reads", 2 * CHECK_READS));
+    }
+}
+
+/// Hooks up the completion interrupt, requests made before this are polled
+pub fn init_irq(dtree: &DeviceTree) {
+    let path = ralloc::format!("/soc/virtio_mmio@{:x}", VIRTIO_BLK_PADDR as usize);
+    let Some(irq) = dtree.search(&path).and_then(|node| node.irq()) else {
+        <VirtioDevice as KSay>::kprint("no interrupt in the devicetree, polling for completions");
+        return;
+    };
+
+    if plic::register(irq, handle_irq) {
+        IRQ_DRIVEN.store(true, Ordering::Relaxed);
+    }
+}
+
+fn handle_irq() {
+    unsafe {
+        let virtio_dev = &mut *VIRTIO_DEVICE;
+        let status = virtio_dev.interrupt_status.read();
+        virtio_dev.interrupt_ack.write(status);
+    }
+
+    COMPLETION_WAIT.wake_all();
+}
+
+/// `regs` is where the device's registers are mapped
+pub fn init_virtio(regs: usize) {
     unsafe {
+        VIRTIO_DEVICE = regs as *mut VirtioDevice;
         let virtio_dev: &'static mut VirtioDevice = &mut *VIRTIO_DEVICE;
         if virtio_dev.magic_val.read() != 0x74726976 {
             panic!("virtio: invalid magic value");
@@ -118,17 +205,22 @@ pub fn init_virtio() {
         virtio_dev.status.or(VIRTIO_STATUS_DRIVER);
         virtio_dev.status.or(VIRTIO_STATUS_FEAT_OK);
 
-        BLK_REQUEST_VQ = virtio_dev.init_queue(0);
+        let vq = virtio_dev.init_queue(0);
 
         virtio_dev.status.write(VIRTIO_STATUS_DRIVER_OK);
 
-        BLK_CAPACITY = virtio_dev.config.read() as usize * SECTOR_SIZE;
+        let capacity = virtio_dev.config.read() as usize * SECTOR_SIZE;
         <VirtioDevice as KSay>::kprint(
-            format_args!("virtio-blk: capacity is 0x{:x}", { BLK_CAPACITY })
+            format_args!("virtio-blk: capacity is 0x{:x}", capacity)
         );
 
-        BLK_REQ_PADDR = GLOBAL_ALLOC.alloc(Layout::new::<VirtioBlockReq>());
-        BLK_REQ = BLK_REQ_PADDR.cast();
+        let req_paddr = GLOBAL_ALLOC.alloc(Layout::new::<VirtioBlockReq>());
+        DISK.call_once(|| SleepMutex::new(Disk {
+            vq,
+            req: req_paddr.cast(),
+            req_paddr,
+            capacity,
+        }, WaitQueue::new()));
     }
 }
 
@@ -183,8 +275,8 @@ pub struct VirtioVirtualQueue {
 // TODO: Better idiomatic rust within function
 pub fn read_disk(buf: &mut [u8], sector: usize) {
     assert!(buf.len() == SECTOR_SIZE);
-    let read_cap = unsafe { BLK_CAPACITY };
-    let cap = read_cap / SECTOR_SIZE;
+    let disk = disk().lock();
+    let cap = disk.capacity / SECTOR_SIZE;
 
     if sector >= cap {
         println!(
@@ -194,12 +286,12 @@ pub fn read_disk(buf: &mut [u8], sector: usize) {
     }
 
     unsafe {
-        let req = BLK_REQ;
+        let req = disk.req;
         (*req).sector = sector;
         (*req).ty = VIRTIO_BLK_T_IN;
 
-        let vq = BLK_REQUEST_VQ;
-        let paddr = BLK_REQ_PADDR;
+        let vq = disk.vq;
+        let paddr = disk.req_paddr;
 
         (*vq).descs[0].addr = paddr;
         (*vq).descs[0].len = (size_of::<u32>() * 2 + size_of::<u64>()) as u32;
@@ -217,7 +309,7 @@ pub fn read_disk(buf: &mut [u8], sector: usize) {
 
         vq.kick(0);
 
-        while vq.is_busy() {}
+        wait_for_completion(vq);
 
         if (*req).status != 0 {
             println!(
@@ -236,8 +328,8 @@ pub fn read_disk(buf: &mut [u8], sector: usize) {
 // Rust style function signature
 // TODO: Better idiomatic rust within function
 pub fn write_disk(buf: &[u8], sector: usize) {
-    let read_cap = unsafe { BLK_CAPACITY };
-    let cap = read_cap / SECTOR_SIZE;
+    let disk = disk().lock();
+    let cap = disk.capacity / SECTOR_SIZE;
 
     if sector >= cap {
         println!(
@@ -247,7 +339,7 @@ pub fn write_disk(buf: &[u8], sector: usize) {
     }
 
     unsafe {
-        let req = BLK_REQ;
+        let req = disk.req;
         (*req).sector = sector;
         (*req).ty = VIRTIO_BLK_T_OUT;
 
@@ -255,8 +347,8 @@ pub fn write_disk(buf: &[u8], sector: usize) {
             .data
             .copy_from_slice(buf);
 
-        let vq = BLK_REQUEST_VQ;
-        let paddr = BLK_REQ_PADDR;
+        let vq = disk.vq;
+        let paddr = disk.req_paddr;
 
         (*vq).descs[0].addr = paddr;
         (*vq).descs[0].len = (size_of::<u32>() * 2 + size_of::<u64>()) as u32;
@@ -274,7 +366,7 @@ pub fn write_disk(buf: &[u8], sector: usize) {
 
         vq.kick(0);
 
-        while vq.is_busy() {}
+        wait_for_completion(vq);
 
         if (*req).status != 0 {
             println!(
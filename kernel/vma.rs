@@ -0,0 +1,233 @@
+//! Per process virtual memory areas
+//!
+//! A process's address space is described by a list of non overlapping, page aligned areas
+//! kept sorted by address. Each area records what it's for, its permissions and where its
+//! contents come from. Pages inside an area don't have to be mapped yet, the page fault
+//! handler zero fills them the first time they are touched.
+
+use core::fmt::Display;
+
+use owo_colors::{OwoColorize, colors::*};
+use ralloc::vec::Vec;
+
+use crate::{
+    frame::FRAME_ALLOC,
+    paging::{PAGE_R, PAGE_SIZE, PAGE_U, PAGE_W, PAGE_X, PAddr, PageTable, PagingError, VAddr},
+    traits::KSay,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaKind {
+    Text,
+    Rodata,
+    Data,
+    Bss,
+    Heap,
+    /// Grows down towards lower addresses on faults below it
+    Stack,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Zero filled on demand
+    Anon,
+    /// Copied from the program image at `offset` when loaded, anything past the end of the
+    /// copied data is zero filled on demand
+    Image { offset: usize },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    /// `PAGE_R`/`PAGE_W`/`PAGE_X`, `PAGE_U` is implied
+    pub flags: usize,
+    pub kind: VmaKind,
+    pub backing: Backing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    Unaligned,
+    /// Part of the range already belongs to another area
+    Overlap,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VmaList {
+    areas: Vec<Vma>,
+}
+
+impl KSay for VmaList {
+    const NAME: &'static str = "vma";
+}
+
+impl Vma {
+    pub fn new(start: usize, end: usize, flags: usize, kind: VmaKind, backing: Backing) -> Vma {
+        Vma { start, end, flags, kind, backing }
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Maps the pages of the area covering `data`, copied in `offset` bytes past its start.
+    /// The rest of the area is left to be zero filled on demand. Pages that are already mapped
+    /// are copied into in place.
+    pub fn populate(&self, table: &mut PageTable, offset: usize, data: &[u8]) -> Result<(), PagingError> {
+        let start = self.start + offset;
+        assert!(start + data.len() <= self.end, "data doesn't fit in the area");
+
+        let first = start & !(PAGE_SIZE - 1);
+        for page in (first..start + data.len()).step_by(PAGE_SIZE) {
+            // Only the first page can start part way in
+            let from = start.max(page);
+            let to = (page + PAGE_SIZE).min(start + data.len());
+            let copy = |frame: *mut u8| unsafe {
+                core::slice::from_raw_parts_mut(frame.add(from - page), to - from)
+                    .copy_from_slice(&data[from - start..to - start]);
+            };
+
+            if let Some((PAddr(frame), _)) = table.translate(VAddr(page as *const ())) {
+                copy(frame as *mut u8);
+                continue;
+            }
+
+            let frame = FRAME_ALLOC.alloc_frame().map_err(|_| PagingError::OutOfMemory)?;
+            copy(frame);
+
+            if let Err(err) = table.map_page(
+                VAddr(page as *const ()),
+                PAddr(frame as *const ()),
+                self.flags | PAGE_U,
+            ) {
+                FRAME_ALLOC.free_frame(frame).expect("frame was just allocated");
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pages of the area currently mapped in `table`
+    pub fn resident(&self, table: &PageTable) -> usize {
+        (self.start..self.end)
+            .step_by(PAGE_SIZE)
+            .filter(|&page| table.translate(VAddr(page as *const ())).is_some())
+            .count()
+    }
+}
+
+impl VmaList {
+    pub const fn new() -> VmaList {
+        VmaList { areas: Vec::new() }
+    }
+
+    pub fn insert(&mut self, vma: Vma) -> Result<(), VmaError> {
+        if vma.start % PAGE_SIZE != 0 || vma.end % PAGE_SIZE != 0 || vma.start >= vma.end {
+            return Err(VmaError::Unaligned);
+        }
+
+        let index = self.areas.partition_point(|area| area.end <= vma.start);
+        if self.areas.get(index).is_some_and(|area| area.start < vma.end) {
+            return Err(VmaError::Overlap);
+        }
+
+        self.areas.insert(index, vma);
+        Ok(())
+    }
+
+    pub fn find(&self, addr: usize) -> Option<&Vma> {
+        let index = self.areas.partition_point(|area| area.end <= addr);
+        self.areas.get(index).filter(|area| area.contains(addr))
+    }
+
+    /// Extends the stack area above `page` down to it, as long as the stack stays within
+    /// `max` bytes and a guard page is left above whatever area sits below it.
+    pub fn grow_stack(&mut self, page: usize, max: usize) -> Option<&Vma> {
+        let index = self.areas.partition_point(|area| area.end <= page);
+        let stack = self.areas.get(index)?;
+
+        if stack.kind != VmaKind::Stack || stack.end - page > max {
+            return None;
+        }
+
+        if index > 0 && self.areas[index - 1].end + PAGE_SIZE > page {
+            return None;
+        }
+
+        self.areas[index].start = page;
+        Some(&self.areas[index])
+    }
+
+    /// Lowest page aligned address at or above `hint` with room for `len` bytes
+    pub fn find_free(&self, hint: usize, len: usize) -> usize {
+        let mut start = hint.next_multiple_of(PAGE_SIZE);
+        for area in self.areas.iter() {
+            if area.end <= start {
+                continue;
+            }
+            if start + len <= area.start {
+                break;
+            }
+            start = area.end;
+        }
+        start
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.iter()
+    }
+
+    /// Prints every area, and how much of it is mapped in `table`
+    pub fn dump(&self, pid: usize, table: &PageTable) {
+        <Self as KSay>::kprint(format_args!("address space of pid {pid}:"));
+        for area in self.iter() {
+            <Self as KSay>::kprint(format_args!(
+                "  {} {}/{} pages",
+                area,
+                area.resident(table),
+                area.len() / PAGE_SIZE
+            ));
+        }
+    }
+}
+
+impl Display for Vma {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let perm = |bit, c| if self.flags & bit != 0 { c } else { '-' };
+        write!(
+            f,
+            "{:#010x}-{:#010x} {}{}{} {:<6} ",
+            self.start,
+            self.end,
+            perm(PAGE_R, 'r'),
+            perm(PAGE_W, 'w'),
+            perm(PAGE_X, 'x'),
+            self.kind.fg::<BrightCyan>(),
+        )?;
+
+        match self.backing {
+            Backing::Anon => write!(f, "anon"),
+            Backing::Image { offset } => write!(f, "image+{offset:#x}"),
+        }
+    }
+}
+
+impl Display for VmaKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let name = match self {
+            VmaKind::Text => "text",
+            VmaKind::Rodata => "rodata",
+            VmaKind::Data => "data",
+            VmaKind::Bss => "bss",
+            VmaKind::Heap => "heap",
+            VmaKind::Stack => "stack",
+        };
+        f.pad(name)
+    }
+}
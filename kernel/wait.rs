@@ -0,0 +1,98 @@
+//! Wait queues
+//!
+//! A process waiting for something (console input, a disk request, a child exiting) blocks on
+//! a [`WaitQueue`] and is taken off the run queue until whoever makes the event happen wakes
+//! it, usually an interrupt handler. Wakeups only mean "check again", every waiter re-checks
+//! its condition before going back to sleep.
+//!
+//! A waiter queues itself and is marked blocked before it checks its condition. Whatever
+//! happens after the check, on this hart or another, finds it on the queue, and a wakeup that
+//! comes before the switch away just puts it back on the run queue.
+
+use ralloc::collections::VecDeque;
+use utils::sync::Park;
+
+use crate::{hart::curr_proc, lock::IrqSpinLock, proc};
+
+/// Mutex that blocks on a wait queue while someone else holds it
+pub type SleepMutex<T> = utils::sync::SleepMutex<T, WaitQueue>;
+
+#[derive(Debug)]
+pub struct WaitQueue {
+    /// Pids of the blocked processes, oldest first
+    waiters: IrqSpinLock<VecDeque<usize>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: IrqSpinLock::new(VecDeque::new()),
+        }
+    }
+
+    /// Blocks the current process until `cond` returns something. Without a process to block
+    /// (early boot, the idle process) it spins instead, which only ends if `cond` doesn't need
+    /// an interrupt to become true.
+    pub fn wait_until<T>(&self, mut cond: impl FnMut() -> Option<T>) -> T {
+        loop {
+            let curr = curr_proc().filter(|&curr| !proc::is_idle(curr));
+            if let Some(curr) = curr {
+                let pid = unsafe { (*curr).pid };
+                {
+                    let mut waiters = self.waiters.lock();
+                    // Still queued if something else woke us
+                    if !waiters.contains(&pid) {
+                        waiters.push_back(pid);
+                    }
+                }
+                proc::prepare_block();
+            }
+
+            if let Some(value) = cond() {
+                if let Some(curr) = curr {
+                    let pid = unsafe { (*curr).pid };
+                    // Still queued, and a wake_one meant for someone else would land on us
+                    let mut waiters = self.waiters.lock();
+                    waiters.retain(|&waiter| waiter != pid);
+                    proc::cancel_block();
+                }
+                return value;
+            }
+
+            match curr {
+                Some(_) => proc::block(),
+                None => core::hint::spin_loop(),
+            }
+        }
+    }
+
+    /// Wakes the process that has been waiting the longest
+    pub fn wake_one(&self) {
+        let pid = self.waiters.lock().pop_front();
+        if let Some(pid) = pid {
+            proc::wake(pid);
+        }
+    }
+
+    pub fn wake_all(&self) {
+        for pid in self.take_waiters() {
+            proc::wake(pid);
+        }
+    }
+
+    /// Empties the queue without waking anyone, for when whatever keeps the queue alive
+    /// can't stay locked while waking
+    pub fn take_waiters(&self) -> VecDeque<usize> {
+        core::mem::take(&mut *self.waiters.lock())
+    }
+}
+
+impl Park for WaitQueue {
+    fn park_until(&self, mut ready: impl FnMut() -> bool) {
+        self.wait_until(|| ready().then_some(()))
+    }
+
+    fn unpark_one(&self) {
+        self.wake_one()
+    }
+}
@@ -3,13 +3,13 @@ set -ev
 
 cargo build --release --bin shell --target riscv64gc-unknown-none-elf
 
-cp target/riscv64gc-unknown-none-elf/debug/shell .shell.elf
+cp target/riscv64gc-unknown-none-elf/release/shell .shell.elf
 
-llvm-objcopy --set-section-flags .bss=alloc,contents -O binary .shell.elf .shell.bin
-llvm-objcopy -Ibinary -Oelf64-littleriscv .shell.bin .shell.bin.o
+# The kernel loads the ELF itself, it's just embedded as a blob
+llvm-objcopy -Ibinary -Oelf64-littleriscv .shell.elf .shell.elf.o
 ./flags.py
 
-RUSTFLAGS="-C link-arg=.shell.bin.o" \
+RUSTFLAGS="-C link-arg=.shell.elf.o" \
     cargo build --bin kernel --target riscv64gc-unknown-none-elf --release
 
 cp target/riscv64gc-unknown-none-elf/release/kernel kernel.elf
@@ -58,6 +58,55 @@ impl Shell {
         match comm {
             "hello" => print!("Hello!"),
             "exit" => exit(),
+            "fork" => match fork() {
+                FileResult::Ok(0) => {
+                    println!("Hello from the child!");
+                    exit();
+                }
+                FileResult::Ok(pid) => {
+                    let mut status = 0;
+                    waitpid(pid, &mut status);
+                    print!("Forked child {pid}, it exited with status {status}");
+                }
+                FileResult::Err(err) => print!("fork failed: {err:?}"),
+            },
+            "exec" => match command_split.next() {
+                Some(path) => {
+                    let err = exec_words(path, command_split);
+                    print!("exec failed: {err:?}");
+                }
+                None => print!("Please provide a program path"),
+            },
+            "run" => match command_split.next() {
+                Some(path) => run(path, command_split),
+                None => print!("Please provide a program path"),
+            },
+            "nice" => {
+                let pid = command_split.next().and_then(|pid| pid.parse().ok());
+                let value = command_split.next().and_then(|value| value.parse().ok());
+                match (pid, value) {
+                    (Some(pid), Some(value)) => match nice(pid, value) {
+                        FileResult::Ok(nice) => print!("pid {pid} is now at nice {}", nice as isize),
+                        FileResult::Err(err) => print!("nice failed: {err:?}"),
+                    },
+                    _ => print!("Usage: nice <pid> <value>, pid 0 is the shell"),
+                }
+            }
+            "sleep" => match command_split.next().and_then(|ms| ms.parse().ok()) {
+                Some(ms) => {
+                    sleep(ms);
+                    print!("Slept for {ms}ms");
+                }
+                None => print!("Usage: sleep <ms>"),
+            },
+            "shutdown" => {
+                let err = shutdown();
+                print!("shutdown failed: {err:?}");
+            }
+            "reboot" => {
+                let err = reboot();
+                print!("reboot failed: {err:?}");
+            }
             "read" => {
                 let mut buf = [0u8; 76];
                 match command_split.next() {
@@ -100,6 +149,81 @@ pub fn exit() -> ! {
     loop {}
 }
 
+/// Returns the child's pid in the parent and 0 in the child
+pub fn fork() -> FileResult {
+    syscall(SYS_FORK, 0, 0, 0, 0)
+}
+
+/// Replaces the calling program with the ELF at `path`. `argv` and `envp` are null
+/// terminated arrays of NUL terminated strings. Only returns on failure.
+pub fn exec(path: &str, argv: &[*const u8], envp: &[*const u8]) -> FileResult {
+    syscall(SYS_EXEC, path.as_ptr() as usize, path.len(), argv.as_ptr() as usize, envp.as_ptr() as usize)
+}
+
+/// Runs `path` with itself and `args` as `argv`
+fn exec_words<'a>(path: &'a str, args: impl Iterator<Item = &'a str>) -> FileResult {
+    // Every word plus its NUL fits, the command line is at most 80 bytes
+    let mut strings = [0u8; 96];
+    let mut argv = [core::ptr::null::<u8>(); 16];
+    let mut used = 0;
+
+    for (i, arg) in core::iter::once(path).chain(args).take(argv.len() - 1).enumerate() {
+        strings[used..used + arg.len()].copy_from_slice(arg.as_bytes());
+        argv[i] = strings[used..].as_ptr();
+        used += arg.len() + 1;
+    }
+
+    exec(path, &argv, &[core::ptr::null()])
+}
+
+/// Waits for any child to exit, returns its pid
+pub fn wait(status: &mut usize) -> FileResult {
+    syscall(SYS_WAIT, status as *mut usize as usize, 0, 0, 0)
+}
+
+/// Waits for the child `pid` to exit
+pub fn waitpid(pid: usize, status: &mut usize) -> FileResult {
+    syscall(SYS_WAITPID, pid, status as *mut usize as usize, 0, 0)
+}
+
+/// Sets the nice value of `pid` (0 for the caller), from -20 to 19. Returns the value set.
+pub fn nice(pid: usize, value: isize) -> FileResult {
+    syscall(SYS_NICE, pid, value as usize, 0, 0)
+}
+
+pub fn sleep(ms: usize) {
+    syscall(SYS_SLEEP, ms, 0, 0, 0);
+}
+
+/// Powers the machine off. Only returns on failure.
+pub fn shutdown() -> FileResult {
+    syscall(SYS_SHUTDOWN, 0, 0, 0, 0)
+}
+
+/// Only returns on failure
+pub fn reboot() -> FileResult {
+    syscall(SYS_REBOOT, 0, 0, 0, 0)
+}
+
+/// Runs a program in a child process and waits for it
+fn run<'a>(path: &'a str, args: impl Iterator<Item = &'a str>) {
+    match fork() {
+        FileResult::Ok(0) => {
+            let err = exec_words(path, args);
+            println!("exec failed: {err:?}");
+            exit();
+        }
+        FileResult::Ok(pid) => {
+            let mut status = 0;
+            match waitpid(pid, &mut status) {
+                FileResult::Ok(_) => print!("{path} exited with status {status}"),
+                FileResult::Err(err) => print!("waitpid failed: {err:?}"),
+            }
+        }
+        FileResult::Err(err) => print!("fork failed: {err:?}"),
+    }
+}
+
 pub fn read(name: &str, buf: &mut [u8]) -> FileResult {
     let str_ptr = name.as_ptr() as usize;
     let str_len = name.len();
@@ -5,12 +5,8 @@ use core::{arch::naked_asm, future::Ready, panic::PanicInfo};
 
 use shell::{Shell, println};
 
-unsafe extern "C" {
-    static mut __stack_top: u8;
-}
-
 #[unsafe(no_mangle)]
-fn main() {
+fn main(_argc: usize, _argv: *const *const u8) {
     println!("Hello! You are now entering the shell");
     let mut buf = [0u8; 76];
     Shell::default().enter();
@@ -22,7 +18,9 @@ fn main() {
 #[unsafe(link_section = ".text.start")]
 pub extern "C" fn start() {
     naked_asm!(
-        "la sp, __stack_top",
+        // The kernel sets up the stack, argc is on top with argv right after
+        "ld a0, 0(sp)",
+        "addi a1, sp, 8",
         "call {main}",
         "call exit",
         main = sym main
@@ -15,6 +15,7 @@
 #![no_std]
 
 pub mod static_alloc;
+pub mod sync;
 pub mod btree;
 pub mod rbtree;
 
@@ -27,6 +28,14 @@ pub mod syscall {
         pub const SYS_EXIT: usize = 3;
         pub const SYS_WRITE: usize = 4;
         pub const SYS_READ: usize = 5;
+        pub const SYS_FORK: usize = 6;
+        pub const SYS_EXEC: usize = 7;
+        pub const SYS_WAIT: usize = 8;
+        pub const SYS_WAITPID: usize = 9;
+        pub const SYS_NICE: usize = 10;
+        pub const SYS_SLEEP: usize = 11;
+        pub const SYS_SHUTDOWN: usize = 12;
+        pub const SYS_REBOOT: usize = 13;
     }
 
     // TODO: Make generic for any type `size_of::<T>() == size_of::<[ok variant value]>`
@@ -87,5 +96,18 @@ pub enum FileResult {
 #[repr(usize)]
 pub enum FileErr {
     FileNotFound,
-    BufferTooLarge
+    BufferTooLarge,
+    OutOfMemory,
+    /// A pointer passed in isn't mapped
+    BadAddress,
+    NotExecutable,
+    /// No child to wait for
+    NoChild,
+    NoSuchProcess,
+    /// The machine can't do that, like shutting down without SBI support for it
+    NotSupported,
+    /// File is too large to load
+    TooLarge,
+    /// An argument is out of range
+    InvalidArgument,
 }
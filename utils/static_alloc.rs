@@ -47,15 +47,22 @@ pub mod init {
         type Target = T;
 
         fn deref(&self) -> &Self::Target {
-            if let InitStatus::Uninit = self.get_status() {
+            // Only whoever moves it out of `Uninit` runs the initializer
+            let won = self.stat.0.compare_exchange(
+                InitStatus::Uninit as u8,
+                InitStatus::InProgress as u8,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            );
+            if won.is_ok() {
                 let f = self.init.take().unwrap();
-                self.set_status(InitStatus::InProgress);
                 f(self.data.as_ptr().cast_init());
-                self.set_status(InitStatus::InProgress);
+                self.set_status(InitStatus::Init);
             }
 
-            // Should really replace with a real compare loop
-            while let InitStatus::InProgress = self.get_status() {}
+            while let InitStatus::InProgress = self.get_status() {
+                core::hint::spin_loop();
+            }
 
             unsafe { &*self.data.as_ptr().cast_init() }
         }
@@ -0,0 +1,460 @@
+//! Synchronization primitives
+//!
+//! Everything here spins except [`SleepMutex`], which leaves the waiting to whoever implements
+//! [`Park`] (the kernel blocks on a wait queue). None of it knows about interrupts, code that
+//! can be interrupted by something taking the same lock needs to turn them off around it.
+
+pub use lazy::Lazy;
+pub use once::Once;
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use sleep::{Park, SleepMutex, SleepMutexGuard};
+pub use ticket::{TicketLock, TicketLockGuard};
+
+pub mod ticket {
+    use core::{
+        cell::UnsafeCell,
+        fmt::Debug,
+        hint::spin_loop,
+        ops::{Deref, DerefMut},
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// Spinlock handed out in the order it was asked for, so nobody waits forever while
+    /// others keep cutting in line
+    pub struct TicketLock<T: ?Sized> {
+        /// Ticket the next locker gets
+        next: AtomicUsize,
+        /// Ticket allowed in right now
+        serving: AtomicUsize,
+        data: UnsafeCell<T>,
+    }
+
+    pub struct TicketLockGuard<'a, T: ?Sized> {
+        lock: &'a TicketLock<T>,
+    }
+
+    unsafe impl<T: ?Sized + Send> Send for TicketLock<T> {}
+    unsafe impl<T: ?Sized + Send> Sync for TicketLock<T> {}
+    unsafe impl<T: ?Sized + Sync> Sync for TicketLockGuard<'_, T> {}
+
+    impl<T> TicketLock<T> {
+        pub const fn new(value: T) -> Self {
+            TicketLock {
+                next: AtomicUsize::new(0),
+                serving: AtomicUsize::new(0),
+                data: UnsafeCell::new(value),
+            }
+        }
+
+        pub fn into_inner(self) -> T {
+            self.data.into_inner()
+        }
+    }
+
+    impl<T: ?Sized> TicketLock<T> {
+        pub fn lock(&self) -> TicketLockGuard<'_, T> {
+            let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+            while self.serving.load(Ordering::Acquire) != ticket {
+                spin_loop();
+            }
+
+            TicketLockGuard { lock: self }
+        }
+
+        /// Only succeeds if nobody holds or is waiting for the lock
+        pub fn try_lock(&self) -> Option<TicketLockGuard<'_, T>> {
+            let serving = self.serving.load(Ordering::Relaxed);
+            self.next
+                .compare_exchange(serving, serving.wrapping_add(1), Ordering::Acquire, Ordering::Relaxed)
+                .ok()
+                .map(|_| TicketLockGuard { lock: self })
+        }
+
+        pub fn is_locked(&self) -> bool {
+            self.next.load(Ordering::Relaxed) != self.serving.load(Ordering::Relaxed)
+        }
+
+        pub fn get_mut(&mut self) -> &mut T {
+            self.data.get_mut()
+        }
+    }
+
+    impl<T: ?Sized + Debug> Debug for TicketLock<T> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            match self.try_lock() {
+                Some(guard) => f.debug_struct("TicketLock").field("data", &&*guard).finish(),
+                None => f.write_str("TicketLock { <locked> }"),
+            }
+        }
+    }
+
+    impl<T: ?Sized> Deref for TicketLockGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            unsafe { &*self.lock.data.get() }
+        }
+    }
+
+    impl<T: ?Sized> DerefMut for TicketLockGuard<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            unsafe { &mut *self.lock.data.get() }
+        }
+    }
+
+    impl<T: ?Sized> Drop for TicketLockGuard<'_, T> {
+        fn drop(&mut self) {
+            // Only the holder ever moves `serving`
+            let serving = self.lock.serving.load(Ordering::Relaxed);
+            self.lock.serving.store(serving.wrapping_add(1), Ordering::Release);
+        }
+    }
+}
+
+pub mod rwlock {
+    use core::{
+        cell::UnsafeCell,
+        fmt::Debug,
+        hint::spin_loop,
+        ops::{Deref, DerefMut},
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// `state` while a writer holds the lock
+    const WRITER: usize = usize::MAX;
+
+    /// Any number of readers or a single writer. Readers can keep a writer waiting for as
+    /// long as they keep overlapping, so it's for data that's read a lot and rarely written.
+    pub struct RwLock<T: ?Sized> {
+        /// Number of readers, or [`WRITER`]
+        state: AtomicUsize,
+        data: UnsafeCell<T>,
+    }
+
+    pub struct RwLockReadGuard<'a, T: ?Sized> {
+        lock: &'a RwLock<T>,
+    }
+
+    pub struct RwLockWriteGuard<'a, T: ?Sized> {
+        lock: &'a RwLock<T>,
+    }
+
+    unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+    unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+    impl<T> RwLock<T> {
+        pub const fn new(value: T) -> Self {
+            RwLock {
+                state: AtomicUsize::new(0),
+                data: UnsafeCell::new(value),
+            }
+        }
+
+        pub fn into_inner(self) -> T {
+            self.data.into_inner()
+        }
+    }
+
+    impl<T: ?Sized> RwLock<T> {
+        pub fn read(&self) -> RwLockReadGuard<'_, T> {
+            loop {
+                if let Some(guard) = self.try_read() {
+                    return guard;
+                }
+                spin_loop();
+            }
+        }
+
+        pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+            let readers = self.state.load(Ordering::Relaxed);
+            // One short of WRITER is as many readers as there can be
+            if readers >= WRITER - 1 {
+                return None;
+            }
+
+            self.state
+                .compare_exchange_weak(readers, readers + 1, Ordering::Acquire, Ordering::Relaxed)
+                .ok()
+                .map(|_| RwLockReadGuard { lock: self })
+        }
+
+        pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+            loop {
+                if let Some(guard) = self.try_write() {
+                    return guard;
+                }
+                spin_loop();
+            }
+        }
+
+        pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+            self.state
+                .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+                .ok()
+                .map(|_| RwLockWriteGuard { lock: self })
+        }
+
+        pub fn get_mut(&mut self) -> &mut T {
+            self.data.get_mut()
+        }
+    }
+
+    impl<T: ?Sized + Debug> Debug for RwLock<T> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            match self.try_read() {
+                Some(guard) => f.debug_struct("RwLock").field("data", &&*guard).finish(),
+                None => f.write_str("RwLock { <locked> }"),
+            }
+        }
+    }
+
+    impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            unsafe { &*self.lock.data.get() }
+        }
+    }
+
+    impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+        fn drop(&mut self) {
+            self.lock.state.fetch_sub(1, Ordering::Release);
+        }
+    }
+
+    impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            unsafe { &*self.lock.data.get() }
+        }
+    }
+
+    impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            unsafe { &mut *self.lock.data.get() }
+        }
+    }
+
+    impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+        fn drop(&mut self) {
+            self.lock.state.store(0, Ordering::Release);
+        }
+    }
+}
+
+pub mod once {
+    use core::{
+        cell::UnsafeCell,
+        fmt::Debug,
+        hint::spin_loop,
+        mem::MaybeUninit,
+        sync::atomic::{AtomicU8, Ordering},
+    };
+
+    const UNINIT: u8 = 0;
+    const RUNNING: u8 = 1;
+    const COMPLETE: u8 = 2;
+
+    /// A value that's initialized exactly once, by whoever gets there first. Everyone else
+    /// waits for it.
+    pub struct Once<T> {
+        state: AtomicU8,
+        data: UnsafeCell<MaybeUninit<T>>,
+    }
+
+    unsafe impl<T: Send> Send for Once<T> {}
+    unsafe impl<T: Send + Sync> Sync for Once<T> {}
+
+    impl<T> Once<T> {
+        pub const fn new() -> Self {
+            Once {
+                state: AtomicU8::new(UNINIT),
+                data: UnsafeCell::new(MaybeUninit::uninit()),
+            }
+        }
+
+        pub fn get(&self) -> Option<&T> {
+            match self.state.load(Ordering::Acquire) {
+                COMPLETE => Some(unsafe { (*self.data.get()).assume_init_ref() }),
+                _ => None,
+            }
+        }
+
+        pub fn is_completed(&self) -> bool {
+            self.state.load(Ordering::Acquire) == COMPLETE
+        }
+
+        /// Runs `f` if nobody has yet, returns the value either way
+        pub fn call_once(&self, f: impl FnOnce() -> T) -> &T {
+            match self.try_call_once(|| Ok::<T, core::convert::Infallible>(f())) {
+                Ok(value) => value,
+            }
+        }
+
+        /// Like [`Once::call_once`], but an error leaves it uninitialized for the next caller
+        /// to try again
+        pub fn try_call_once<E>(&self, f: impl FnOnce() -> Result<T, E>) -> Result<&T, E> {
+            loop {
+                match self.state.compare_exchange(UNINIT, RUNNING, Ordering::Acquire, Ordering::Acquire) {
+                    Ok(_) => {
+                        return match f() {
+                            Ok(value) => {
+                                unsafe { (*self.data.get()).write(value) };
+                                self.state.store(COMPLETE, Ordering::Release);
+                                Ok(unsafe { (*self.data.get()).assume_init_ref() })
+                            }
+                            Err(err) => {
+                                self.state.store(UNINIT, Ordering::Release);
+                                Err(err)
+                            }
+                        };
+                    }
+                    Err(COMPLETE) => return Ok(unsafe { (*self.data.get()).assume_init_ref() }),
+                    // Someone else is initializing it, wait to see how that goes
+                    Err(_) => spin_loop(),
+                }
+            }
+        }
+    }
+
+    impl<T> Default for Once<T> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<T: Debug> Debug for Once<T> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            match self.get() {
+                Some(value) => f.debug_tuple("Once").field(value).finish(),
+                None => f.write_str("Once(<uninit>)"),
+            }
+        }
+    }
+
+    impl<T> Drop for Once<T> {
+        fn drop(&mut self) {
+            if *self.state.get_mut() == COMPLETE {
+                unsafe { self.data.get_mut().assume_init_drop() };
+            }
+        }
+    }
+}
+
+pub mod lazy {
+    use core::{cell::Cell, ops::Deref};
+
+    use super::Once;
+
+    /// A value computed by `F` the first time it's used
+    pub struct Lazy<T, F = fn() -> T> {
+        once: Once<T>,
+        init: Cell<Option<F>>,
+    }
+
+    // `init` is only taken by whoever wins the `Once`
+    unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+    impl<T, F> Lazy<T, F> {
+        pub const fn new(init: F) -> Self {
+            Lazy {
+                once: Once::new(),
+                init: Cell::new(Some(init)),
+            }
+        }
+    }
+
+    impl<T, F: FnOnce() -> T> Lazy<T, F> {
+        pub fn force(this: &Self) -> &T {
+            this.once.call_once(|| match this.init.take() {
+                Some(init) => init(),
+                None => panic!("Lazy instance has previously been poisoned"),
+            })
+        }
+    }
+
+    impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            Lazy::force(self)
+        }
+    }
+}
+
+pub mod sleep {
+    use core::{
+        cell::UnsafeCell,
+        ops::{Deref, DerefMut},
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    /// How a [`SleepMutex`] waits for the lock, the kernel blocks on a wait queue
+    pub trait Park {
+        /// Sleeps until `ready` returns true, checking again every time it's woken. The caller
+        /// must not be parked anymore once this returns, or [`Park::unpark_one`] could pick
+        /// it instead of a real waiter and that wakeup would be lost.
+        fn park_until(&self, ready: impl FnMut() -> bool);
+
+        /// Wakes the longest parked waiter, if there is one
+        fn unpark_one(&self);
+    }
+
+    /// Mutex that sleeps instead of spinning while someone else holds it, so it can be held
+    /// for a long time, even across a context switch
+    pub struct SleepMutex<T: ?Sized, P> {
+        locked: AtomicBool,
+        park: P,
+        data: UnsafeCell<T>,
+    }
+
+    pub struct SleepMutexGuard<'a, T: ?Sized, P: Park> {
+        lock: &'a SleepMutex<T, P>,
+    }
+
+    unsafe impl<T: ?Sized + Send, P: Send> Send for SleepMutex<T, P> {}
+    unsafe impl<T: ?Sized + Send, P: Sync> Sync for SleepMutex<T, P> {}
+
+    impl<T, P> SleepMutex<T, P> {
+        pub const fn new(value: T, park: P) -> Self {
+            SleepMutex {
+                locked: AtomicBool::new(false),
+                park,
+                data: UnsafeCell::new(value),
+            }
+        }
+    }
+
+    impl<T: ?Sized, P: Park> SleepMutex<T, P> {
+        pub fn lock(&self) -> SleepMutexGuard<'_, T, P> {
+            self.park.park_until(|| !self.locked.swap(true, Ordering::Acquire));
+            SleepMutexGuard { lock: self }
+        }
+
+        pub fn try_lock(&self) -> Option<SleepMutexGuard<'_, T, P>> {
+            (!self.locked.swap(true, Ordering::Acquire)).then_some(SleepMutexGuard { lock: self })
+        }
+    }
+
+    impl<T: ?Sized, P: Park> Deref for SleepMutexGuard<'_, T, P> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            unsafe { &*self.lock.data.get() }
+        }
+    }
+
+    impl<T: ?Sized, P: Park> DerefMut for SleepMutexGuard<'_, T, P> {
+        fn deref_mut(&mut self) -> &mut T {
+            unsafe { &mut *self.lock.data.get() }
+        }
+    }
+
+    impl<T: ?Sized, P: Park> Drop for SleepMutexGuard<'_, T, P> {
+        fn drop(&mut self) {
+            self.lock.locked.store(false, Ordering::Release);
+            self.lock.park.unpark_one();
+        }
+    }
+}